@@ -0,0 +1,136 @@
+// conversions between srgb bytes and linear light, where averaging colours behaves physically,
+// and oklab, where it looks even
+
+use std::{fmt, str::FromStr};
+
+pub fn srgb_to_linear(c: u8) -> f64 {
+    let c = c as f64 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(c: f64) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round() as u8
+}
+
+// perceived brightness 0-1 of an srgb colour
+pub fn luma([r, g, b]: [u8; 3]) -> f64 {
+    (0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64) / 255.0
+}
+
+// oklab (lightness, green-red, blue-yellow), where equal steps look equally different
+pub fn srgb_to_oklab([r, g, b]: [u8; 3]) -> [f64; 3] {
+    let [r, g, b] = [r, g, b].map(srgb_to_linear);
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
+
+pub fn oklab_to_srgb([lightness, a, b]: [f64; 3]) -> [u8; 3] {
+    let l = (lightness + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+    let m = (lightness - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+    let s = (lightness - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+    [
+        4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+        -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+        -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s,
+    ]
+    .map(linear_to_srgb)
+}
+
+// where colours are mixed when blending births, fading dying cells and laying frames on the background
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColourSpace {
+    // straight on the stored bytes, as image's blend does. mixes come out darker than they should
+    #[default]
+    Srgb,
+    // linear light, mixes as light itself would
+    Linear,
+    // oklab, mixes and fades in even steps of perceived lightness and hue
+    Oklab,
+}
+
+impl FromStr for ColourSpace {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_whitespace().collect::<String>().to_lowercase();
+        match s.as_str() {
+            "srgb" | "gamma" => Ok(ColourSpace::Srgb),
+            "linear" | "linear-rgb" => Ok(ColourSpace::Linear),
+            "oklab" => Ok(ColourSpace::Oklab),
+            _ => Err(format!(
+                "colour space '{}' should be \"srgb\", \"linear\" or \"oklab\"",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ColourSpace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ColourSpace::Srgb => write!(f, "srgb"),
+            ColourSpace::Linear => write!(f, "linear"),
+            ColourSpace::Oklab => write!(f, "oklab"),
+        }
+    }
+}
+
+impl ColourSpace {
+    fn decode(&self, rgb: [u8; 3]) -> [f64; 3] {
+        match self {
+            ColourSpace::Srgb => rgb.map(|c| c as f64),
+            ColourSpace::Linear => rgb.map(srgb_to_linear),
+            ColourSpace::Oklab => srgb_to_oklab(rgb),
+        }
+    }
+
+    fn encode(&self, colour: [f64; 3]) -> [u8; 3] {
+        match self {
+            ColourSpace::Srgb => colour.map(|c| c.round().clamp(0.0, 255.0) as u8),
+            ColourSpace::Linear => colour.map(linear_to_srgb),
+            ColourSpace::Oklab => oklab_to_srgb(colour),
+        }
+    }
+
+    // the colour fraction t of the way from one colour to another
+    pub fn mix(&self, from: [u8; 3], to: [u8; 3], t: f64) -> [u8; 3] {
+        // the ends stay exact, converting there and back can be out by one
+        if t <= 0.0 {
+            return from;
+        } else if t >= 1.0 {
+            return to;
+        }
+        let (from, to) = (self.decode(from), self.decode(to));
+        self.encode([0, 1, 2].map(|c| from[c] + (to[c] - from[c]) * t))
+    }
+
+    // average of some colours, black if there are none
+    pub fn mean(&self, colours: &[[u8; 3]]) -> [u8; 3] {
+        if colours.is_empty() {
+            return [0, 0, 0];
+        }
+        let mut sum = [0.0; 3];
+        for colour in colours {
+            let colour = self.decode(*colour);
+            for c in 0..3 {
+                sum[c] += colour[c];
+            }
+        }
+        self.encode(sum.map(|c| c / colours.len() as f64))
+    }
+}
@@ -1,20 +1,278 @@
 use image::{
-    self,
-    imageops::{index_colors, BiLevel},
-    DynamicImage, GenericImageView, ImageBuffer, Pixel, Rgba, RgbaImage,
+    self, DynamicImage, GenericImageView, GrayImage, ImageBuffer, Luma, LumaA, Pixel, Rgb,
+    RgbImage, Rgba, RgbaImage,
 };
 
-use crate::Mode;
+use std::{fmt, str::FromStr};
+
+use crate::{grid::Grid, Mode};
 use indicatif::ProgressBar;
-use rand::Rng;
+use pixelator::{luma, ColourSpace, Dither, FrameSink, Palette, Threshold, Timing};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 
 pub enum CellState {
     Alive,
+    // refractory stage of a Generations rule, counts up from 1 until the cell is Dead
+    Dying(u8),
     Dead,
 }
 
+// Life-like rule in B/S notation, e.g. B3/S23 (Conway), B36/S23 (HighLife), B2/S (Seeds).
+// bit n of each mask is set if n live neighbours cause a birth / let a cell survive.
+// Generations rules add a state count, e.g. B2/S/C3 (Brian's Brain): cells that fail to survive
+// pass through states - 2 dying stages before they are dead and can be born again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub birth: u16,
+    pub survival: u16,
+    pub states: u8,
+}
+
+impl Rule {
+    pub fn born(&self, n: u8) -> bool {
+        self.birth & (1 << n) != 0
+    }
+
+    pub fn survives(&self, n: u8) -> bool {
+        self.survival & (1 << n) != 0
+    }
+
+    // state a cell moves into once it stops being alive / advances a dying stage
+    pub fn next_dying(&self, stage: u8) -> CellState {
+        if stage + 2 < self.states {
+            CellState::Dying(stage + 1)
+        } else {
+            CellState::Dead
+        }
+    }
+}
+
+impl Default for Rule {
+    fn default() -> Self {
+        // B3/S23
+        Rule {
+            birth: 1 << 3,
+            survival: (1 << 2) | (1 << 3),
+            states: 2,
+        }
+    }
+}
+
+fn parse_neighbour_counts(digits: &str) -> Result<u16, String> {
+    let mut mask = 0u16;
+    for c in digits.chars() {
+        match c.to_digit(10) {
+            Some(n) if n <= 8 => mask |= 1 << n,
+            _ => return Err(format!("invalid neighbour count '{}' in rule", c)),
+        }
+    }
+    Ok(mask)
+}
+
+fn parse_states(digits: &str) -> Result<u8, String> {
+    match digits.parse::<u8>() {
+        Ok(states) if states >= 2 => Ok(states),
+        _ => Err(format!(
+            "state count '{}' should be a number from 2 to 255",
+            digits
+        )),
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+    // "B36/S23", "B2/S/C3" or the older survival/birth(/states) forms "23/36", "345/2/4"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_whitespace().collect::<String>().to_lowercase();
+        let parts = s.split('/').collect::<Vec<&str>>();
+        if !(2..=3).contains(&parts.len()) {
+            return Err(format!("rule '{}' should look like B3/S23 or B2/S/C3", s));
+        }
+
+        let states = match parts.get(2) {
+            Some(part) => parse_states(part.strip_prefix('c').unwrap_or(part))?,
+            None => 2,
+        };
+
+        let (mut birth, mut survival) = (None, None);
+        for part in &parts[..2] {
+            if let Some(digits) = part.strip_prefix('b') {
+                birth = Some(parse_neighbour_counts(digits)?);
+            } else if let Some(digits) = part.strip_prefix('s') {
+                survival = Some(parse_neighbour_counts(digits)?);
+            }
+        }
+
+        match (birth, survival) {
+            (Some(birth), Some(survival)) => Ok(Rule {
+                birth,
+                survival,
+                states,
+            }),
+            // no prefixes, S/B notation
+            (None, None) => Ok(Rule {
+                survival: parse_neighbour_counts(parts[0])?,
+                birth: parse_neighbour_counts(parts[1])?,
+                states,
+            }),
+            _ => Err(format!("rule '{}' needs both a B and an S part", s)),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = |mask: u16| {
+            (0..=8)
+                .filter(|n| mask & (1 << n) != 0)
+                .map(|n| n.to_string())
+                .collect::<String>()
+        };
+        write!(f, "B{}/S{}", digits(self.birth), digits(self.survival))?;
+        if self.states > 2 {
+            write!(f, "/C{}", self.states)?;
+        }
+        Ok(())
+    }
+}
+
+// how the original pixel of a dying cell is treated over its refractory stages.
+// each stage moves the pixel a step closer to the target, reaching it as the cell dies
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DyingColour {
+    // fade out to transparent
+    #[default]
+    Fade,
+    // fade to black, staying opaque
+    Darken,
+    // drain colour to grey
+    Desaturate,
+    // shift towards a fixed colour
+    Tint((u8, u8, u8)),
+}
+
+impl DyingColour {
+    // move pixel towards the target colour by fraction t, mixing the colour in space
+    fn apply(&self, pix: &mut Rgba<u8>, t: f32, space: ColourSpace) {
+        let lerp = |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * t).round() as u8;
+        let [r, g, b, a] = pix.0;
+        let target = match *self {
+            DyingColour::Fade => [r, g, b, 0],
+            DyingColour::Darken => [0, 0, 0, a],
+            DyingColour::Desaturate => {
+                let luma = pix.to_luma()[0];
+                [luma, luma, luma, a]
+            }
+            DyingColour::Tint((tr, tg, tb)) => [tr, tg, tb, a],
+        };
+        if space == ColourSpace::Srgb {
+            for (channel, to) in pix.channels_mut().iter_mut().zip(target) {
+                *channel = lerp(*channel, to);
+            }
+            return;
+        }
+        let [tr, tg, tb, ta] = target;
+        let [r, g, b] = space.mix([r, g, b], [tr, tg, tb], t as f64);
+        *pix = Rgba([r, g, b, lerp(a, ta)]);
+    }
+}
+
+impl FromStr for DyingColour {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_whitespace().collect::<String>().to_lowercase();
+        match s.as_str() {
+            "fade" | "f" => Ok(Self::Fade),
+            "darken" | "d" => Ok(Self::Darken),
+            "desaturate" | "grey" | "gray" | "g" => Ok(Self::Desaturate),
+            _ => {
+                let colour = hex_rgb::convert_hexcode_to_rgb(s)?;
+                Ok(Self::Tint((colour.red, colour.green, colour.blue)))
+            }
+        }
+    }
+}
+
+// what lies beyond the edges of the image
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Topology {
+    // everything outside is dead, patterns die at the border
+    #[default]
+    DeadBorder,
+    // everything outside is alive
+    AliveBorder,
+    // left/right and top/bottom edges join up
+    Torus,
+    // like a torus, but crossing the top/bottom edge flips left and right
+    Klein,
+    // the edges reflect back into the image
+    Mirror,
+}
+
+impl Topology {
+    // maps a possibly out-of-bounds coordinate onto the cell it refers to.
+    // None if it lies outside the universe (a dead or alive border)
+    pub fn locate(&self, (x, y): (i64, i64), (width, height): (u32, u32)) -> Option<(u32, u32)> {
+        let (w, h) = (width as i64, height as i64);
+        let inside = |x: i64, y: i64| (0..w).contains(&x) && (0..h).contains(&y);
+        match self {
+            Topology::DeadBorder | Topology::AliveBorder => {
+                if inside(x, y) {
+                    Some((x as u32, y as u32))
+                } else {
+                    None
+                }
+            }
+            Topology::Torus => Some((x.rem_euclid(w) as u32, y.rem_euclid(h) as u32)),
+            Topology::Klein => {
+                let x = if (0..h).contains(&y) { x } else { w - 1 - x };
+                Some((x.rem_euclid(w) as u32, y.rem_euclid(h) as u32))
+            }
+            Topology::Mirror => {
+                let reflect = |i: i64, n: i64| {
+                    if i < 0 {
+                        -1 - i
+                    } else if i >= n {
+                        2 * n - 1 - i
+                    } else {
+                        i
+                    }
+                };
+                Some((reflect(x, w) as u32, reflect(y, h) as u32))
+            }
+        }
+    }
+}
+
+impl FromStr for Topology {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_whitespace().collect::<String>().to_lowercase();
+        match s.as_str() {
+            "dead" | "wall" => Ok(Self::DeadBorder),
+            "alive" => Ok(Self::AliveBorder),
+            "torus" | "wrap" => Ok(Self::Torus),
+            "klein" => Ok(Self::Klein),
+            "mirror" | "reflect" => Ok(Self::Mirror),
+            _ => Err(format!("unknown topology '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Topology::DeadBorder => write!(f, "dead"),
+            Topology::AliveBorder => write!(f, "alive"),
+            Topology::Torus => write!(f, "torus"),
+            Topology::Klein => write!(f, "klein"),
+            Topology::Mirror => write!(f, "mirror"),
+        }
+    }
+}
+
 // create new blank rgb image (dark or light)
 // each cell surviving cell stays the same
 // each new birth combines colours of surrounding cells
@@ -22,166 +280,497 @@ pub enum CellState {
 
 #[derive(Clone)]
 pub struct Universe {
-    pub cells: Vec<Vec<CellState>>,
+    pub cells: Grid,
     pub image: ImageBuffer<Rgba<u8>, Vec<u8>>,
 }
 
-pub fn begin_life(img: DynamicImage, generations: u64, decay: u8, mode: &Mode) -> Vec<RgbaImage> {
-    // set decay 0-255, make equal to 2^n for smooth results. 32 is about right to witness pulsing
-    // light mode creates life on lightest pixels, dark mode creates life on darkest pixels
+// everything deciding how one generation turns into the next
+#[derive(Clone, Debug, Default)]
+pub struct Physics {
+    pub rule: Rule,
+    pub topology: Topology,
+    pub dying: DyingColour,
+    // how much alpha dead cells lose each step
+    pub decay: u8,
+    // newly coloured cells snap back onto this, keeping the animation on palette
+    pub palette: Option<Palette>,
+    // how a newborn cell's colour comes from its parents
+    pub inheritance: Inheritance,
+    // chance in births is worked out from this, so the same seed gives the same animation
+    pub seed: u64,
+    // where births and dying cells mix their colours
+    pub colour_space: ColourSpace,
+}
 
-    let mut slides: Vec<RgbaImage> = Vec::new();
+// how a newborn cell takes its colour from the live neighbours that brought it to life
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Inheritance {
+    // each parent laid over the last, favouring the later ones
+    #[default]
+    Blend,
+    // average of the parents
+    Mean,
+    // the colour most parents share, the mean if none do
+    Majority,
+    // one parent picked at random
+    Random,
+    // average of the parents in oklab, keeps mixes bright and even
+    Oklab,
+    // average of the parents, each channel nudged at random by up to this much
+    Mutate(u8),
+}
 
-    let (width, height) = img.dimensions();
-    let area = width * height;
+impl FromStr for Inheritance {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_whitespace().collect::<String>().to_lowercase();
+        match s.split_once(':') {
+            Some(("mutate" | "mutation", amount)) => amount
+                .parse::<u8>()
+                .map(Inheritance::Mutate)
+                .map_err(|_| format!("mutation '{}' should be 0-255", amount)),
+            _ => match s.as_str() {
+                "blend" => Ok(Inheritance::Blend),
+                "mean" | "average" => Ok(Inheritance::Mean),
+                "majority" => Ok(Inheritance::Majority),
+                "random" => Ok(Inheritance::Random),
+                "oklab" => Ok(Inheritance::Oklab),
+                "mutate" | "mutation" => Ok(Inheritance::Mutate(16)),
+                _ => Err(format!(
+                    "birth colour '{}' should be \"blend\", \"mean\", \"majority\", \"random\", \"oklab\" or \"mutate[:N]\"",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl Inheritance {
+    // opaque colour of a cell born to these parents, mixed in space. chance is a random number
+    // for this birth
+    pub fn colour(&self, parents: &[Rgba<u8>], chance: u64, space: ColourSpace) -> Rgba<u8> {
+        if parents.is_empty() {
+            return Rgba([0, 0, 0, 255]);
+        }
+        let [r, g, b] = match self {
+            Inheritance::Blend if space == ColourSpace::Srgb => {
+                let mut blended_pixel = Rgba([0; 4]);
+                for parent in parents {
+                    blended_pixel.blend(parent)
+                }
+                [blended_pixel[0], blended_pixel[1], blended_pixel[2]]
+            }
+            Inheritance::Blend => {
+                // the first parent is laid over nothing, so shows through whole
+                let first = parents[0];
+                let mut blended = [first[0], first[1], first[2]];
+                for parent in &parents[1..] {
+                    let t = parent[3] as f64 / 255.0;
+                    blended = space.mix(blended, [parent[0], parent[1], parent[2]], t);
+                }
+                blended
+            }
+            Inheritance::Mean => mean_rgb(parents, space),
+            Inheritance::Majority => {
+                let count = |colour: &Rgba<u8>| {
+                    parents
+                        .iter()
+                        .filter(|p| p.to_rgb() == colour.to_rgb())
+                        .count()
+                };
+                // the winner has to outnumber every other colour, a tie would go to whichever
+                // neighbour happened to come first or last
+                let most = parents.iter().map(count).max().unwrap_or(0);
+                let winner = parents.iter().find(|parent| count(parent) == most);
+                let tied = parents.iter().any(|parent| {
+                    count(parent) == most && Some(parent.to_rgb()) != winner.map(|w| w.to_rgb())
+                });
+                match winner {
+                    Some(winner) if most > 1 && !tied => [winner[0], winner[1], winner[2]],
+                    _ => mean_rgb(parents, space),
+                }
+            }
+            Inheritance::Random => {
+                let parent = parents[(chance % parents.len() as u64) as usize];
+                [parent[0], parent[1], parent[2]]
+            }
+            Inheritance::Oklab => mean_rgb(parents, ColourSpace::Oklab),
+            Inheritance::Mutate(amount) => {
+                let mean = mean_rgb(parents, space);
+                let span = 2 * *amount as u64 + 1;
+                // a different slice of the random bits for each channel
+                [0, 1, 2].map(|c| {
+                    let nudge = ((chance >> (c * 16)) & 0xFFFF) % span;
+                    (mean[c] as i64 + nudge as i64 - *amount as i64).clamp(0, 255) as u8
+                })
+            }
+        };
+        Rgba([r, g, b, 255])
+    }
+}
+
+fn mean_rgb(pixels: &[Rgba<u8>], space: ColourSpace) -> [u8; 3] {
+    if space != ColourSpace::Srgb {
+        let colours: Vec<[u8; 3]> = pixels.iter().map(|pix| [pix[0], pix[1], pix[2]]).collect();
+        return space.mean(&colours);
+    }
+    let mut sum = [0u32; 3];
+    for pix in pixels {
+        for c in 0..3 {
+            sum[c] += pix[c] as u32;
+        }
+    }
+    let n = pixels.len().max(1) as u32;
+    sum.map(|c| ((c + n / 2) / n) as u8)
+}
+
+// random bits for the cell at (x, y) in a generation, the same whichever thread asks
+fn cell_hash(seed: u64, generation: u64, x: u32, y: u32) -> u64 {
+    // splitmix64 finaliser
+    let mut z = seed
+        ^ generation.wrapping_mul(0x9E3779B97F4A7C15)
+        ^ ((x as u64) << 32 | y as u64).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+// what makes a cell more likely to start alive in random mode
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Weighting {
+    // every cell the same
+    #[default]
+    Uniform,
+    // brighter pixels
+    Luma,
+    // darker pixels
+    Dark,
+    // more opaque pixels
+    Alpha,
+}
+
+impl FromStr for Weighting {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_whitespace().collect::<String>().to_lowercase();
+        match s.as_str() {
+            "uniform" | "none" => Ok(Weighting::Uniform),
+            "luma" | "light" | "luminance" => Ok(Weighting::Luma),
+            "dark" => Ok(Weighting::Dark),
+            "alpha" => Ok(Weighting::Alpha),
+            _ => Err(format!(
+                "weighting '{}' should be \"uniform\", \"luma\", \"dark\" or \"alpha\"",
+                s
+            )),
+        }
+    }
+}
+
+impl Weighting {
+    fn weight(&self, pix: &Rgba<u8>) -> f64 {
+        match self {
+            Weighting::Uniform => 1.0,
+            Weighting::Luma => luma([pix[0], pix[1], pix[2]]),
+            Weighting::Dark => 1.0 - luma([pix[0], pix[1], pix[2]]),
+            Weighting::Alpha => pix[3] as f64 / 255.0,
+        }
+    }
+}
+
+// share of cells alive at the start of random mode. matches the old way of picking as many random
+// cells as there are pixels, which often hit the same cell twice
+pub const RANDOM_DENSITY: f64 = 1.0 - 1.0 / std::f64::consts::E;
+
+// how the first generation is picked out of the image
+#[derive(Clone, Debug)]
+pub struct Seeding {
+    // light / dark split for the light and dark modes
+    pub threshold: Threshold,
+    // dithers brightness into a scatter of live cells rather than solid regions
+    pub dither: Dither,
+    // share of cells alive at the start of random mode, on average
+    pub density: f64,
+    pub weighting: Weighting,
+    // the same seed always gives the same animation
+    pub seed: u64,
+    // bright pixels of this mask pick the live cells whatever the mode, same size as the image
+    pub mask: Option<GrayImage>,
+    // a separate universe for each of red, green and blue
+    pub split_channels: bool,
+}
+
+impl Default for Seeding {
+    fn default() -> Self {
+        Seeding {
+            threshold: Threshold::default(),
+            dither: Dither::default(),
+            density: RANDOM_DENSITY,
+            weighting: Weighting::default(),
+            seed: 0,
+            mask: None,
+            split_channels: false,
+        }
+    }
+}
+
+pub fn begin_life(
+    img: DynamicImage,
+    generations: u64,
+    mode: &Mode,
+    seeding: &Seeding,
+    physics: &Physics,
+    timing: &Timing,
+    sink: &mut dyn FrameSink,
+) {
+    // set decay 0-255, make equal to 2^n for smooth results. 32 is about right to witness pulsing
+    // light mode creates life on lightest pixels, dark mode creates life on darkest pixels
+    // each slide goes straight to the sink, only the current generation is kept in memory
 
     //let mapped = map_onto_whitespace(&img, &mode);
     //mapped.save("output/mapped.png");
 
-    let cells = match mode {
-        Mode::Random => {
-            let mut cells = vec![vec!(CellState::Dead; width as usize); height as usize];
-            //create random life
-            let mut rng = rand::thread_rng();
-            for _n in 0..area {
-                let x = rng.gen_range(0..width);
-                let y = rng.gen_range(0..height);
-                cells[y as usize][x as usize] = CellState::Alive;
-            }
-            cells
-        }
-        _ => map_onto_cells(&img, mode),
+    let mut universes: Vec<Universe> = if seeding.split_channels {
+        // one universe per channel, each seeded from and colouring only its own channel
+        let rgba = img.into_rgba8();
+        (0..3)
+            .map(|c| {
+                let (width, height) = rgba.dimensions();
+                let source = ImageBuffer::from_fn(width, height, |x, y| {
+                    let pix = rgba.get_pixel(x, y);
+                    LumaA([pix[c], pix[3]])
+                });
+                let image = ImageBuffer::from_fn(width, height, |x, y| {
+                    let pix = rgba.get_pixel(x, y);
+                    let mut channel = Rgba([0, 0, 0, pix[3]]);
+                    channel[c] = pix[c];
+                    channel
+                });
+                let seeding = Seeding {
+                    seed: seeding.seed.wrapping_add(c as u64),
+                    ..seeding.clone()
+                };
+                Universe {
+                    cells: seed_cells(&DynamicImage::ImageLumaA8(source), mode, &seeding),
+                    image,
+                }
+            })
+            .collect()
+    } else {
+        vec![Universe {
+            cells: seed_cells(&img, mode, seeding),
+            image: img.into_rgba8(),
+        }]
     };
 
-    let mut universe = Universe {
-        cells,
-        image: img.into_rgba8(),
+    // hold on the original pixelated version before life begins
+    let intro_ms = if generations == 0 {
+        timing.final_ms
+    } else {
+        timing.intro_ms
     };
-
-    // start vector with a few original pixelated versions
-    for _ in 0..5 {
-        slides.push(universe.image.clone());
-    }
+    write_universes(&universes, intro_ms, sink);
 
     let bar = ProgressBar::new(generations);
 
-    println!("starting the game of life...");
-    for _ in 0..generations {
+    eprintln!("starting the game of life...");
+    for generation in 1..=generations {
         // try do it without clone...
-        universe = step(universe.cells, &universe.image, (width, height), decay);
-        let slide = universe.image.clone();
-        slides.push(slide);
+        universes = universes
+            .into_iter()
+            .map(|universe| step(universe.cells, &universe.image, physics, generation))
+            .collect();
+        let delay_ms = if generation == generations {
+            timing.final_ms
+        } else {
+            timing.delay_ms
+        };
+        write_universes(&universes, delay_ms, sink);
         bar.inc(1);
     }
     //step_universe(universe, (width, height))
-    slides
 }
 
+fn seed_cells(img: &DynamicImage, mode: &Mode, seeding: &Seeding) -> Grid {
+    match (&seeding.mask, mode) {
+        (Some(mask), _) => map_onto_cells(
+            &DynamicImage::ImageLuma8(mask.clone()),
+            &Mode::Light,
+            seeding,
+        ),
+        (None, Mode::Random) => random_cells(&img.to_rgba8(), seeding),
+        (None, _) => map_onto_cells(img, mode, seeding),
+    }
+}
+
+// a single universe is the frame as is. split channels take their own channel from each universe,
+// as opaque as the most opaque of them, each channel scaled down by how faded its own universe is
+fn write_universes(universes: &[Universe], delay_ms: u16, sink: &mut dyn FrameSink) {
+    if let [universe] = universes {
+        sink.write_frame(&universe.image, delay_ms);
+        return;
+    }
+
+    let (width, height) = universes[0].image.dimensions();
+    let frame = ImageBuffer::from_fn(width, height, |x, y| {
+        let channels: Vec<&Rgba<u8>> = universes.iter().map(|u| u.image.get_pixel(x, y)).collect();
+        let alpha = channels.iter().map(|pix| pix[3]).max().unwrap_or(0);
+        if alpha == 0 {
+            return Rgba([0, 0, 0, 0]);
+        }
+        let mut pix = Rgba([0, 0, 0, alpha]);
+        for (c, channel) in channels.iter().enumerate() {
+            pix[c] = (channel[c] as u32 * channel[3] as u32 / alpha as u32) as u8;
+        }
+        pix
+    });
+    sink.write_frame(&frame, delay_ms);
+}
+
+// generation numbers the step, so chance in births differs from one generation to the next
 pub fn step(
-    frame: Vec<Vec<CellState>>,
+    frame: Grid,
     img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
-    (width, height): (u32, u32),
-    alpha_decay_per_step: u8,
+    physics: &Physics,
+    generation: u64,
 ) -> Universe {
     let mut next_img = img.clone();
-    let mut next_frame = vec![vec!(CellState::Dead; width as usize); height as usize];
-
-    for (y, row) in &mut frame.iter().enumerate() {
-        for (x, cell) in row.iter().enumerate() {
-            let n = neighbors((x as i16, y as i16), &frame);
-
-            match *cell {
-                // @ 2..=3?
-                CellState::Alive if (n == 2) | (n == 3) => {
-                    next_frame[y][x] = CellState::Alive;
-                }
+    let next_frame = frame.evolve(&physics.rule, &physics.topology);
+    let row_len = img.width() as usize * 4;
 
-                CellState::Dead if n == 3 => {
-                    let coords = neighbors_coords((x as i16, y as i16), &frame, (width, height));
-                    let mut blended_pixel = Rgba([0; 4]);
-                    for coord in &coords {
-                        blended_pixel.blend(img.get_pixel(coord.0, coord.1))
-                    }
-
-                    //does this work? To make opaque
-                    blended_pixel[3] = 255;
-                    next_img.put_pixel(x as u32, y as u32, blended_pixel);
-                    next_frame[y][x] = CellState::Alive
-                }
-
-                _ => {
-                    // reduces all remaining pixels' alpha value by decay
-                    let pix = next_img.get_pixel_mut(x as u32, y as u32);
-                    if let [_r, _g, _b, a] = pix.channels_mut() {
-                        if *a >= alpha_decay_per_step {
-                            *a -= alpha_decay_per_step;
-                        } else {
-                            // immediately reduce to invisible if dead
-                            *a = 0;
-                        }
-                    }
-                }
-            }
+    if row_len > 0 {
+        #[cfg(feature = "parallel")]
+        {
+            use rayon::prelude::*;
+            // a few bands per thread so uneven rows don't leave cores idle
+            let band = (img.height() as usize / (rayon::current_num_threads() * 4)).max(1);
+            next_img
+                .par_chunks_mut(band * row_len)
+                .enumerate()
+                .for_each(|(b, rows)| {
+                    let first = (b * band) as u32;
+                    step_band(first, rows, (&frame, &next_frame), img, physics, generation);
+                });
         }
+
+        #[cfg(not(feature = "parallel"))]
+        step_band(
+            0,
+            &mut next_img,
+            (&frame, &next_frame),
+            img,
+            physics,
+            generation,
+        );
     }
-    //return cells vec and new image
+
+    //return cells and new image
     Universe {
         cells: next_frame,
         image: next_img,
     }
 }
 
-pub fn neighbors((col, row): (i16, i16), cells: &Vec<Vec<CellState>>) -> u8 {
-    // costly ?? vs inputing (w, h) as a parameter
-    let (height, width) = (cells.len(), cells[0].len());
-    let mut total = 0i16;
-    // make sure self wont be included in total
-    if cells[row as usize][col as usize] == CellState::Alive {
-        total -= 1;
+// paints a run of whole rows of the next image (given as raw rgba bytes), starting at row first
+fn step_band(
+    first: u32,
+    rows: &mut [u8],
+    (frame, next_frame): (&Grid, &Grid),
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    physics: &Physics,
+    generation: u64,
+) {
+    let row_len = img.width() as usize * 4;
+    for (i, row) in rows.chunks_mut(row_len).enumerate() {
+        let y = first + i as u32;
+        step_row(y, row, (frame, next_frame), img, physics, generation);
     }
-    for y in row - 1..=row + 1 {
-        for x in col - 1..=col + 1 {
-            if (x < 0) | (y < 0) | (x >= width as i16) | (y >= height as i16) {
-                //println!("nope @ ({},{})", x, y);
-            } else {
-                let cell = cells[y as usize][x as usize];
-                match cell {
-                    CellState::Alive => {
-                        total += 1;
-                        //println!("yep @ ({},{})", x, y)
-                    }
-                    CellState::Dead => {
-                        //println!("dead cell @ ({},{})", x, y)
+}
+
+// paints row y of the next image (given as raw rgba bytes) from how its cells changed
+fn step_row(
+    y: u32,
+    row: &mut [u8],
+    (frame, next_frame): (&Grid, &Grid),
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    physics: &Physics,
+    generation: u64,
+) {
+    for (x, pix) in row.chunks_exact_mut(4).enumerate() {
+        let x = x as u32;
+        let pix = Rgba::from_slice_mut(pix);
+        match (frame.get(x, y), next_frame.get(x, y)) {
+            // survivors keep their colour
+            (CellState::Alive, CellState::Alive) => {}
+
+            (_, CellState::Alive) => {
+                let coords = neighbors_coords((x as i64, y as i64), frame, &physics.topology);
+                let parents: Vec<Rgba<u8>> = coords
+                    .iter()
+                    .map(|coord| *img.get_pixel(coord.0, coord.1))
+                    .collect();
+                let chance = cell_hash(physics.seed, generation, x, y);
+                *pix = physics
+                    .inheritance
+                    .colour(&parents, chance, physics.colour_space);
+                snap(pix, physics);
+            }
+
+            (_, CellState::Dying(stage)) => {
+                // refractory cells take the dying colour treatment instead of decaying.
+                // stages run 1..=states - 2, so the last one lands on the target
+                physics.dying.apply(
+                    pix,
+                    1.0 / (physics.rule.states - 1 - stage) as f32,
+                    physics.colour_space,
+                );
+                snap(pix, physics);
+            }
+
+            (_, CellState::Dead) => {
+                // reduces all remaining pixels' alpha value by decay
+                if let [_r, _g, _b, a] = pix.channels_mut() {
+                    if *a >= physics.decay {
+                        *a -= physics.decay;
+                    } else {
+                        // immediately reduce to invisible if dead
+                        *a = 0;
                     }
                 }
             }
         }
     }
-    total as u8
+}
+
+fn snap(pix: &mut Rgba<u8>, physics: &Physics) {
+    if let Some(palette) = &physics.palette {
+        let [r, g, b] = palette.nearest([pix[0], pix[1], pix[2]]);
+        *pix = Rgba([r, g, b, pix[3]]);
+    }
 }
 
 // make next generation live cell a blend of self + surrounding cells
 pub fn neighbors_coords(
-    (col, row): (i16, i16),
-    cells: &[Vec<CellState>],
-    (width, height): (u32, u32),
+    (col, row): (i64, i64),
+    cells: &Grid,
+    topology: &Topology,
 ) -> Vec<(u32, u32)> {
+    let (width, height) = (cells.width(), cells.height());
     let mut coords: Vec<(u32, u32)> = Vec::new();
 
     for y in row - 1..=row + 1 {
         for x in col - 1..=col + 1 {
-            if (x < 0) | (y < 0) | (x >= width as i16) | (y >= height as i16) {
-                //println!("nope @ ({},{})", x, y);
-            } else {
-                let cell = cells[y as usize][x as usize];
-                match cell {
-                    CellState::Alive => {
-                        coords.push((x as u32, y as u32));
-                        //println!("yep @ ({},{})", x, y)
-                    }
-                    CellState::Dead => {
-                        //println!("dead cell @ ({},{})", x, y)
-                    }
+            match topology.locate((x, y), (width, height)) {
+                Some((x, y)) if cells.is_alive(x, y) => coords.push((x, y)),
+                Some(_) => {
+                    //println!("dead cell @ ({},{})", x, y)
+                }
+                // the living border takes the colour of the nearest edge pixel
+                None if *topology == Topology::AliveBorder => coords.push((
+                    x.clamp(0, width as i64 - 1) as u32,
+                    y.clamp(0, height as i64 - 1) as u32,
+                )),
+                None => {
+                    //println!("nope @ ({},{})", x, y);
                 }
             }
         }
@@ -189,24 +778,134 @@ pub fn neighbors_coords(
     coords
 }
 
-pub fn map_onto_cells(img: &DynamicImage, mode: &Mode) -> Vec<Vec<CellState>> {
-    println!("splitting image by luminance...");
+// each cell alive with a chance following its pixel's weight, averaging out at the density
+fn random_cells(img: &RgbaImage, seeding: &Seeding) -> Grid {
+    let (width, height) = img.dimensions();
+    let mut cells = Grid::new(width, height);
+    let mut rng = StdRng::seed_from_u64(seeding.seed);
+
+    let weights: Vec<f64> = img
+        .pixels()
+        .map(|pix| seeding.weighting.weight(pix))
+        .collect();
+    let mean = weights.iter().sum::<f64>() / weights.len().max(1) as f64;
+    if mean == 0.0 {
+        return cells;
+    }
+    let density = spread_density(&weights, mean, seeding.density);
+
+    for (i, weight) in weights.iter().enumerate() {
+        let chance = (density * weight / mean).min(1.0);
+        if rng.gen::<f64>() < chance {
+            cells.set(i as u32 % width, i as u32 / width, CellState::Alive);
+        }
+    }
+    cells
+}
+
+// density to give the weighted chances so they still average out at the density once capped
+// at 1. cells too heavy for their share fill up first and the rest split what they couldn't take
+fn spread_density(weights: &[f64], mean: f64, density: f64) -> f64 {
+    let heaviest = weights.iter().copied().fold(0.0, f64::max);
+    if density * heaviest / mean <= 1.0 {
+        return density;
+    }
+
+    let mut sorted: Vec<f64> = weights.iter().copied().filter(|w| *w > 0.0).collect();
+    sorted.sort_unstable_by(|a, b| b.total_cmp(a));
+    let wanted = density * weights.len() as f64;
+    let mut rest: f64 = sorted.iter().sum();
+    for (full, weight) in sorted.iter().enumerate() {
+        if rest <= 0.0 {
+            break;
+        }
+        let scale = (wanted - full as f64) / rest;
+        if scale * weight <= 1.0 {
+            return scale * mean;
+        }
+        rest -= weight;
+    }
+    // more live cells wanted than there are cells with any weight, all of those start alive
+    f64::MAX
+}
+
+// strength of the brightness gradient at each pixel, strongest edge 255
+fn sobel(luma: &GrayImage) -> GrayImage {
+    let (w, h) = luma.dimensions();
+    // edge pixels repeat outwards
+    let at = |x: i64, y: i64| {
+        luma.get_pixel(
+            x.clamp(0, w as i64 - 1) as u32,
+            y.clamp(0, h as i64 - 1) as u32,
+        )[0] as f32
+    };
+    let magnitudes: Vec<f32> = (0..h as i64)
+        .flat_map(|y| (0..w as i64).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let gx = (at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1))
+                - (at(x - 1, y - 1) + 2.0 * at(x - 1, y) + at(x - 1, y + 1));
+            let gy = (at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1))
+                - (at(x - 1, y - 1) + 2.0 * at(x, y - 1) + at(x + 1, y - 1));
+            (gx * gx + gy * gy).sqrt()
+        })
+        .collect();
+    let strongest = magnitudes.iter().copied().fold(0.0, f32::max).max(1.0);
+    GrayImage::from_raw(
+        w,
+        h,
+        magnitudes
+            .iter()
+            .map(|m| (m * 255.0 / strongest).round() as u8)
+            .collect(),
+    )
+    .unwrap()
+}
+
+// 1 for light, 0 for dark, dithered so the share of light cells follows the brightness.
+// brightness is stretched so the threshold level lands halfway
+fn dither_cells(luma: &GrayImage, threshold: &Threshold, dither: &Dither) -> GrayImage {
+    let level = threshold.level(luma) as f32;
+    eprintln!("dithering around {}", level);
+    let grey = RgbImage::from_fn(luma.width(), luma.height(), |x, y| {
+        let v = luma.get_pixel(x, y)[0] as f32;
+        let v = if v <= level {
+            v * 128.0 / (level + 1.0)
+        } else {
+            128.0 + (v - level) * 127.0 / (255.0 - level)
+        };
+        Rgb([v.round() as u8; 3])
+    });
+    let mut black_and_white = Palette::new(vec![[0, 0, 0], [255, 255, 255]]);
+    let indices = black_and_white.map(&grey, dither);
+    GrayImage::from_raw(luma.width(), luma.height(), indices).unwrap()
+}
+
+pub fn map_onto_cells(img: &DynamicImage, mode: &Mode, seeding: &Seeding) -> Grid {
+    eprintln!("splitting image by luminance...");
     let (w, h) = img.dimensions();
-    let img_luma = img.to_luma8();
-    let cmap = BiLevel;
-    let mut palletised = index_colors(&img_luma, &cmap);
+    let img_luma = match mode {
+        Mode::Edges => sobel(&img.to_luma8()),
+        Mode::Alpha => {
+            let rgba = img.to_rgba8();
+            GrayImage::from_fn(w, h, |x, y| Luma([rgba.get_pixel(x, y)[3]]))
+        }
+        _ => img.to_luma8(),
+    };
+    let mut palletised = match seeding.dither {
+        Dither::None => seeding.threshold.split(&img_luma),
+        dither => dither_cells(&img_luma, &seeding.threshold, &dither),
+    };
 
     // create cell frame of dead cells
-    // create h vectors or w length. index into using vec[y][x]
-    let mut cells = vec![vec!(CellState::Dead; w as usize); h as usize];
+    let mut cells = Grid::new(w, h);
 
     match mode {
-        Mode::Light => {
+        // strong edges and opaque pixels count as light
+        Mode::Light | Mode::Edges | Mode::Alpha => {
             for (x, y, pix) in palletised.enumerate_pixels_mut() {
-                let (x, y) = (x as usize, y as usize);
                 match pix.0[0] {
                     0 => {}
-                    1 => cells[y][x] = CellState::Alive,
+                    1 => cells.set(x, y, CellState::Alive),
                     _ => panic!(),
                 }
             }
@@ -215,9 +914,8 @@ pub fn map_onto_cells(img: &DynamicImage, mode: &Mode) -> Vec<Vec<CellState>> {
 
         Mode::Dark => {
             for (x, y, pix) in palletised.enumerate_pixels_mut() {
-                let (x, y) = (x as usize, y as usize);
                 match pix.0[0] {
-                    0 => cells[y][x] = CellState::Alive,
+                    0 => cells.set(x, y, CellState::Alive),
                     1 => {}
                     _ => panic!(),
                 }
@@ -230,3 +928,132 @@ pub fn map_onto_cells(img: &DynamicImage, mode: &Mode) -> Vec<Vec<CellState>> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn majority_needs_a_clear_winner() {
+        let (red, blue, green) = (
+            Rgba([255, 0, 0, 255]),
+            Rgba([0, 0, 255, 255]),
+            Rgba([0, 255, 0, 255]),
+        );
+        let colour =
+            |parents: &[Rgba<u8>]| Inheritance::Majority.colour(parents, 0, ColourSpace::Srgb);
+        assert_eq!(colour(&[red, blue, red, green]), red);
+        assert_eq!(colour(&[blue, red, green, red]), red);
+        // two pairs tie, so the order they come in mustn't matter
+        let mean = Rgba([128, 0, 128, 255]);
+        assert_eq!(colour(&[red, red, blue, blue]), mean);
+        assert_eq!(colour(&[blue, blue, red, red]), mean);
+        assert_eq!(colour(&[red, blue, red, blue]), mean);
+        assert_eq!(colour(&[red, blue, green]), Rgba([85, 85, 85, 255]));
+    }
+
+    // heavy cells capped at certain life hand their excess to the rest, so the density still holds
+    #[test]
+    fn weighted_seeding_keeps_the_density() {
+        let (width, height) = (200, 200);
+        // a tenth of the image bright, the rest nearly black
+        let img = RgbaImage::from_fn(width, height, |x, _| {
+            if x % 10 == 0 {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([25, 25, 25, 255])
+            }
+        });
+        for (weighting, density) in [
+            (Weighting::Luma, 0.5),
+            (Weighting::Luma, 0.95),
+            (Weighting::Uniform, RANDOM_DENSITY),
+        ] {
+            let seeding = Seeding {
+                density,
+                weighting,
+                seed: 3,
+                ..Default::default()
+            };
+            let cells = random_cells(&img, &seeding);
+            let alive = (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .filter(|&(x, y)| cells.is_alive(x, y))
+                .count();
+            let share = alive as f64 / (width * height) as f64;
+            assert!(
+                (share - density).abs() < 0.01,
+                "{:?} at {} gave {}",
+                weighting,
+                density,
+                share
+            );
+        }
+    }
+
+    // painting the next image in bands, as the parallel path does, gives the same bytes as one pass
+    #[test]
+    fn banded_steps_match_serial() {
+        let (width, height) = (37, 23);
+        let mut rng = StdRng::seed_from_u64(5);
+        for (rule, topology, inheritance) in [
+            ("B3/S23", Topology::Klein, Inheritance::Random),
+            ("B2/S/C3", Topology::Mirror, Inheritance::Mutate(16)),
+            ("B3/S345/C5", Topology::Torus, Inheritance::Blend),
+        ] {
+            let physics = Physics {
+                rule: rule.parse().unwrap(),
+                topology,
+                dying: DyingColour::Darken,
+                inheritance,
+                seed: 9,
+                ..Default::default()
+            };
+            let mut cells = Grid::new(width, height);
+            for y in 0..height {
+                for x in 0..width {
+                    if rng.gen_bool(0.4) {
+                        cells.set(x, y, CellState::Alive);
+                    }
+                }
+            }
+            let mut image = RgbaImage::from_fn(width, height, |_, _| Rgba(rng.gen()));
+
+            for generation in 1..=6 {
+                let next_cells = cells.evolve(&physics.rule, &physics.topology);
+                let mut serial = image.clone();
+                step_band(
+                    0,
+                    &mut serial,
+                    (&cells, &next_cells),
+                    &image,
+                    &physics,
+                    generation,
+                );
+
+                let row_len = width as usize * 4;
+                for band in [1, 2, 3, 7, height as usize] {
+                    let mut banded = image.clone();
+                    for (b, rows) in banded.chunks_mut(band * row_len).enumerate() {
+                        let first = (b * band) as u32;
+                        step_band(
+                            first,
+                            rows,
+                            (&cells, &next_cells),
+                            &image,
+                            &physics,
+                            generation,
+                        );
+                    }
+                    assert_eq!(banded, serial, "{} bands of {} rows", rule, band);
+                }
+
+                let universe = step(cells, &image, &physics, generation);
+                assert_eq!(universe.image, serial, "{} step", rule);
+                assert_eq!(universe.cells, next_cells);
+                cells = universe.cells;
+                image = universe.image;
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::{borrow::Cow, fs::File};
+
+use image::{RgbImage, RgbaImage};
+use indicatif::ProgressBar;
+
+use crate::{
+    create_background, gif_delay, set_gif_repeat, BackgroundColour, ColourSpace, Dither, FrameSink,
+    Loop, Palette, Quantiser,
+};
+
+// index left free in the palette for pixels that haven't changed since the last frame
+const TRANSPARENT: u8 = 255;
+
+// most slides are nearly identical, so the palette is learnt from a sample of every slide
+// rather than all of their pixels
+const SAMPLE_PIXELS: usize = 1 << 20;
+
+// gif sharing one palette between every slide, so colours don't flicker from frame to frame.
+// after the first frame only the rectangle that changed is stored, with unchanged pixels left
+// transparent. the palette needs every slide, so they are held in memory until finish()
+pub struct GlobalGifSink {
+    file_path: String,
+    dimensions: (u32, u32),
+    background: BackgroundColour,
+    space: ColourSpace,
+    loops: Loop,
+    quantiser: Quantiser,
+    dither: Dither,
+    slides: Vec<(RgbImage, u16)>,
+}
+
+impl GlobalGifSink {
+    pub fn new(
+        file_path: &str,
+        dimensions: (u32, u32),
+        background: BackgroundColour,
+        space: ColourSpace,
+        loops: Loop,
+        quantiser: Quantiser,
+        dither: Dither,
+    ) -> GlobalGifSink {
+        eprintln!("creating gif with a global palette at {}", file_path);
+
+        GlobalGifSink {
+            file_path: file_path.to_owned(),
+            dimensions,
+            background,
+            space,
+            loops,
+            quantiser,
+            dither,
+            slides: Vec::new(),
+        }
+    }
+
+    fn palette(&self) -> Palette {
+        let total: usize = self.slides.iter().map(|(img, _)| img.len() / 3).sum();
+        let step = total.div_ceil(SAMPLE_PIXELS).max(1);
+        let sample: Vec<[u8; 3]> = self
+            .slides
+            .iter()
+            .flat_map(|(img, _)| img.pixels())
+            .step_by(step)
+            .map(|pix| pix.0)
+            .collect();
+        Palette::build(&self.quantiser, &sample, TRANSPARENT as usize)
+    }
+}
+
+impl FrameSink for GlobalGifSink {
+    fn write_frame(&mut self, slide: &RgbaImage, delay_ms: u16) {
+        let img = create_background(slide.clone(), &self.background, self.space).to_rgb8();
+        self.slides.push((img, delay_ms));
+    }
+
+    fn finish(&mut self) {
+        eprintln!("building palette...");
+        let mut palette = self.palette();
+        let mut colours = palette.rgb();
+        colours.resize(256 * 3, 0);
+
+        let (width, height) = self.dimensions;
+        let image = File::create(&self.file_path).expect("couldn't save gif to path:");
+        let mut encoder = gif::Encoder::new(image, width as u16, height as u16, &colours).unwrap();
+        set_gif_repeat(&mut encoder, self.loops);
+
+        eprintln!("encoding gif...");
+        let bar = ProgressBar::new(self.slides.len() as u64);
+        let mut previous: Option<Vec<u8>> = None;
+
+        for (img, delay_ms) in self.slides.drain(..) {
+            let indices = palette.map(&img, &self.dither);
+
+            let mut frame = match &previous {
+                None => full_frame(&indices, (width, height)),
+                Some(previous) => changed_frame(previous, &indices, (width, height)),
+            };
+            frame.delay = gif_delay(delay_ms);
+            // draw each frame over the last
+            frame.dispose = gif::DisposalMethod::Keep;
+            encoder.write_frame(&frame).unwrap();
+
+            previous = Some(indices);
+            bar.inc(1);
+        }
+    }
+}
+
+fn full_frame(indices: &[u8], (width, height): (u32, u32)) -> gif::Frame<'_> {
+    gif::Frame {
+        width: width as u16,
+        height: height as u16,
+        buffer: Cow::Borrowed(indices),
+        ..gif::Frame::default()
+    }
+}
+
+// just the rectangle around pixels that differ from the previous frame, the rest transparent
+fn changed_frame(
+    previous: &[u8],
+    indices: &[u8],
+    (width, height): (u32, u32),
+) -> gif::Frame<'static> {
+    let (width, height) = (width as usize, height as usize);
+    let (mut left, mut top, mut right, mut bottom) = (width, height, 0, 0);
+    for y in 0..height {
+        for x in 0..width {
+            if previous[y * width + x] != indices[y * width + x] {
+                left = left.min(x);
+                top = top.min(y);
+                right = right.max(x + 1);
+                bottom = bottom.max(y + 1);
+            }
+        }
+    }
+
+    // nothing changed, a single transparent pixel still holds the delay
+    if left >= right {
+        return gif::Frame {
+            width: 1,
+            height: 1,
+            buffer: Cow::Owned(vec![TRANSPARENT]),
+            transparent: Some(TRANSPARENT),
+            ..gif::Frame::default()
+        };
+    }
+
+    let mut buffer = Vec::with_capacity((right - left) * (bottom - top));
+    for y in top..bottom {
+        for x in left..right {
+            let i = y * width + x;
+            buffer.push(if previous[i] == indices[i] {
+                TRANSPARENT
+            } else {
+                indices[i]
+            });
+        }
+    }
+    gif::Frame {
+        left: left as u16,
+        top: top as u16,
+        width: (right - left) as u16,
+        height: (bottom - top) as u16,
+        buffer: Cow::Owned(buffer),
+        transparent: Some(TRANSPARENT),
+        ..gif::Frame::default()
+    }
+}
@@ -0,0 +1,483 @@
+use std::borrow::Cow;
+
+use crate::conway::{CellState, Rule, Topology};
+
+// bit-packed cell grid. each row is stored as ceil(width / 64) words, cell x of a row being
+// bit x % 64 of word x / 64. bits past the right edge of a row are always kept zero.
+// dying stages of Generations rules live in a separate byte per cell, only allocated once used
+#[derive(Clone, Debug)]
+pub struct Grid {
+    width: u32,
+    height: u32,
+    words_per_row: usize,
+    alive: Vec<u64>,
+    dying: Vec<u64>,
+    stages: Vec<u8>,
+}
+
+// a neighbouring row as seen through the topology, plus the cells just beyond either end
+struct Row<'a> {
+    bits: Cow<'a, [u64]>,
+    west: u64,
+    east: u64,
+}
+
+impl Grid {
+    // a grid of dead cells
+    pub fn new(width: u32, height: u32) -> Grid {
+        let words_per_row = (width as usize).div_ceil(64);
+        Grid {
+            width,
+            height,
+            words_per_row,
+            alive: vec![0; words_per_row * height as usize],
+            dying: vec![0; words_per_row * height as usize],
+            stages: Vec::new(),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn index(&self, x: u32, y: u32) -> (usize, u64) {
+        (
+            y as usize * self.words_per_row + x as usize / 64,
+            1 << (x % 64),
+        )
+    }
+
+    pub fn is_alive(&self, x: u32, y: u32) -> bool {
+        let (i, bit) = self.index(x, y);
+        self.alive[i] & bit != 0
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> CellState {
+        let (i, bit) = self.index(x, y);
+        if self.alive[i] & bit != 0 {
+            CellState::Alive
+        } else if self.dying[i] & bit != 0 {
+            CellState::Dying(self.stages[y as usize * self.width as usize + x as usize])
+        } else {
+            CellState::Dead
+        }
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, state: CellState) {
+        let (i, bit) = self.index(x, y);
+        self.alive[i] &= !bit;
+        self.dying[i] &= !bit;
+        match state {
+            CellState::Alive => self.alive[i] |= bit,
+            CellState::Dying(stage) => {
+                if self.stages.is_empty() {
+                    self.stages = vec![0; self.width as usize * self.height as usize];
+                }
+                self.dying[i] |= bit;
+                self.stages[y as usize * self.width as usize + x as usize] = stage;
+            }
+            CellState::Dead => {}
+        }
+    }
+
+    fn row(&self, y: u32) -> &[u64] {
+        let start = y as usize * self.words_per_row;
+        &self.alive[start..start + self.words_per_row]
+    }
+
+    // valid bits of the last word in a row
+    fn last_word_mask(&self) -> u64 {
+        match self.width % 64 {
+            0 => !0,
+            bits => (1 << bits) - 1,
+        }
+    }
+
+    fn bit(bits: &[u64], x: u32) -> u64 {
+        (bits[x as usize / 64] >> (x % 64)) & 1
+    }
+
+    fn reversed(&self, bits: &[u64]) -> Vec<u64> {
+        let mut reversed = vec![0; self.words_per_row];
+        for x in 0..self.width {
+            reversed[x as usize / 64] |= Grid::bit(bits, self.width - 1 - x) << (x % 64);
+        }
+        reversed
+    }
+
+    // a row whose ends join up with each other
+    fn wrapped<'a>(&self, bits: Cow<'a, [u64]>) -> Row<'a> {
+        let (west, east) = (Grid::bit(&bits, self.width - 1), Grid::bit(&bits, 0));
+        Row { bits, west, east }
+    }
+
+    // row y (which may lie beyond the top or bottom edge) as the topology sees it
+    fn row_through(&self, y: i64, topology: &Topology) -> Row<'_> {
+        let h = self.height as i64;
+        let inside = (0..h).contains(&y);
+
+        match topology {
+            Topology::DeadBorder | Topology::AliveBorder => {
+                let edge = (*topology == Topology::AliveBorder) as u64;
+                if inside {
+                    Row {
+                        bits: Cow::Borrowed(self.row(y as u32)),
+                        west: edge,
+                        east: edge,
+                    }
+                } else {
+                    let mut bits = vec![if edge == 1 { !0 } else { 0 }; self.words_per_row];
+                    if let Some(last) = bits.last_mut() {
+                        *last &= self.last_word_mask();
+                    }
+                    Row {
+                        bits: Cow::Owned(bits),
+                        west: edge,
+                        east: edge,
+                    }
+                }
+            }
+            Topology::Torus => self.wrapped(Cow::Borrowed(self.row(y.rem_euclid(h) as u32))),
+            Topology::Klein => {
+                let row = self.row(y.rem_euclid(h) as u32);
+                if inside {
+                    self.wrapped(Cow::Borrowed(row))
+                } else {
+                    self.wrapped(Cow::Owned(self.reversed(row)))
+                }
+            }
+            Topology::Mirror => {
+                let y = if y < 0 {
+                    -1 - y
+                } else if y >= h {
+                    2 * h - 1 - y
+                } else {
+                    y
+                };
+                let bits = self.row(y as u32);
+                let (west, east) = (Grid::bit(bits, 0), Grid::bit(bits, self.width - 1));
+                Row {
+                    bits: Cow::Borrowed(bits),
+                    west,
+                    east,
+                }
+            }
+        }
+    }
+
+    // word i of the row shifted so each cell holds its western / eastern neighbour
+    fn west_of(&self, row: &Row, i: usize) -> u64 {
+        let carry = if i == 0 {
+            row.west
+        } else {
+            row.bits[i - 1] >> 63
+        };
+        (row.bits[i] << 1) | carry
+    }
+
+    fn east_of(&self, row: &Row, i: usize) -> u64 {
+        if i + 1 == self.words_per_row {
+            (row.bits[i] >> 1) | (row.east << ((self.width - 1) % 64))
+        } else {
+            (row.bits[i] >> 1) | (row.bits[i + 1] << 63)
+        }
+    }
+
+    // next generation under the rule, counting neighbours of 64 cells at a time
+    pub fn evolve(&self, rule: &Rule, topology: &Topology) -> Grid {
+        if self.width == 0 {
+            return Grid::new(self.width, self.height);
+        }
+
+        // rows only read the current generation, so they can be worked out in any order
+        #[cfg(feature = "parallel")]
+        let rows: Vec<NextRow> = {
+            use rayon::prelude::*;
+            (0..self.height)
+                .into_par_iter()
+                .map(|y| self.evolve_row(y, rule, topology))
+                .collect()
+        };
+        #[cfg(not(feature = "parallel"))]
+        let rows: Vec<NextRow> = (0..self.height)
+            .map(|y| self.evolve_row(y, rule, topology))
+            .collect();
+
+        let mut next = Grid {
+            alive: Vec::with_capacity(self.alive.len()),
+            dying: Vec::with_capacity(self.dying.len()),
+            stages: Vec::new(),
+            ..*self
+        };
+        for row in rows {
+            next.alive.extend(row.alive);
+            next.dying.extend(row.dying);
+            next.stages.extend(row.stages);
+        }
+        next
+    }
+
+    fn evolve_row(&self, y: u32, rule: &Rule, topology: &Topology) -> NextRow {
+        let mut next = NextRow {
+            alive: vec![0; self.words_per_row],
+            dying: vec![0; self.words_per_row],
+            stages: if rule.states > 2 {
+                vec![0; self.width as usize]
+            } else {
+                Vec::new()
+            },
+        };
+
+        let above = self.row_through(y as i64 - 1, topology);
+        let centre = self.row_through(y as i64, topology);
+        let below = self.row_through(y as i64 + 1, topology);
+
+        for i in 0..self.words_per_row {
+            let neighbours = [
+                self.west_of(&above, i),
+                above.bits[i],
+                self.east_of(&above, i),
+                self.west_of(&centre, i),
+                self.east_of(&centre, i),
+                self.west_of(&below, i),
+                below.bits[i],
+                self.east_of(&below, i),
+            ];
+
+            // bit-sliced sum of the eight neighbour masks, planes[k] holding bit k of each count
+            let mut planes = [0u64; 4];
+            for mut carry in neighbours {
+                for plane in planes.iter_mut() {
+                    let sum = *plane ^ carry;
+                    carry &= *plane;
+                    *plane = sum;
+                }
+            }
+
+            let (mut birth, mut survival) = (0u64, 0u64);
+            for n in 0..=8u8 {
+                if !rule.born(n) && !rule.survives(n) {
+                    continue;
+                }
+                let count_is_n = planes.iter().enumerate().fold(!0u64, |acc, (k, plane)| {
+                    acc & if (n >> k) & 1 == 1 { *plane } else { !*plane }
+                });
+                if rule.born(n) {
+                    birth |= count_is_n;
+                }
+                if rule.survives(n) {
+                    survival |= count_is_n;
+                }
+            }
+
+            let word = y as usize * self.words_per_row + i;
+            let mask = if i + 1 == self.words_per_row {
+                self.last_word_mask()
+            } else {
+                !0
+            };
+            let (alive, dying) = (self.alive[word], self.dying[word]);
+            next.alive[i] = ((alive & survival) | (!alive & !dying & birth)) & mask;
+
+            // cells leaving the alive state or already refractory move on a stage
+            if rule.states > 2 {
+                let mut ageing = (alive & !survival) | dying;
+                while ageing != 0 {
+                    let bit = ageing.trailing_zeros();
+                    ageing &= ageing - 1;
+                    let x = i as u32 * 64 + bit;
+                    let stage = match self.get(x, y) {
+                        CellState::Dying(stage) => stage,
+                        _ => 0,
+                    };
+                    if let CellState::Dying(stage) = rule.next_dying(stage) {
+                        next.dying[i] |= 1 << bit;
+                        next.stages[x as usize] = stage;
+                    }
+                }
+            }
+        }
+        next
+    }
+}
+
+// the same cells in the same states. stages are allocated lazily and left behind when a cell
+// stops dying, so only the stages of cells that are dying count
+impl PartialEq for Grid {
+    fn eq(&self, other: &Grid) -> bool {
+        self.width == other.width
+            && self.height == other.height
+            && self.alive == other.alive
+            && self.dying == other.dying
+            && (0..self.height).all(|y| (0..self.width).all(|x| self.get(x, y) == other.get(x, y)))
+    }
+}
+
+impl Eq for Grid {}
+
+// one row of the next generation, in the same layout as Grid
+struct NextRow {
+    alive: Vec<u64>,
+    dying: Vec<u64>,
+    stages: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    const TOPOLOGIES: [Topology; 5] = [
+        Topology::DeadBorder,
+        Topology::AliveBorder,
+        Topology::Torus,
+        Topology::Klein,
+        Topology::Mirror,
+    ];
+
+    fn random_grid(width: u32, height: u32, rule: &Rule, rng: &mut StdRng) -> Grid {
+        let mut grid = Grid::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let state = match rng.gen_range(0..4) {
+                    0 | 1 => CellState::Dead,
+                    2 => CellState::Alive,
+                    _ if rule.states > 2 => CellState::Dying(rng.gen_range(1..rule.states - 1)),
+                    _ => CellState::Alive,
+                };
+                grid.set(x, y, state);
+            }
+        }
+        grid
+    }
+
+    // one cell at a time, neighbours found through Topology::locate
+    fn naive_evolve(grid: &Grid, rule: &Rule, topology: &Topology) -> Grid {
+        let (width, height) = (grid.width(), grid.height());
+        let mut next = Grid::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut n = 0;
+                for dy in -1..=1 {
+                    for dx in -1..=1 {
+                        if (dx, dy) == (0, 0) {
+                            continue;
+                        }
+                        let at = (x as i64 + dx, y as i64 + dy);
+                        n += match topology.locate(at, (width, height)) {
+                            Some((nx, ny)) => grid.is_alive(nx, ny) as u8,
+                            None => (*topology == Topology::AliveBorder) as u8,
+                        };
+                    }
+                }
+                let state = match grid.get(x, y) {
+                    CellState::Alive if rule.survives(n) => CellState::Alive,
+                    CellState::Alive => rule.next_dying(0),
+                    CellState::Dying(stage) => rule.next_dying(stage),
+                    CellState::Dead if rule.born(n) => CellState::Alive,
+                    CellState::Dead => CellState::Dead,
+                };
+                next.set(x, y, state);
+            }
+        }
+        next
+    }
+
+    #[test]
+    fn evolve_matches_naive_neighbour_count() {
+        let rules = ["B3/S23", "B36/S23", "B2/S", "B2/S/C3", "B3/S345/C5"];
+        let mut rng = StdRng::seed_from_u64(1);
+        for rule in rules {
+            let rule: Rule = rule.parse().unwrap();
+            for topology in &TOPOLOGIES {
+                for (width, height) in [(1, 5), (63, 4), (64, 3), (65, 6), (130, 5), (7, 1)] {
+                    let mut grid = random_grid(width, height, &rule, &mut rng);
+                    for generation in 0..4 {
+                        let expected = naive_evolve(&grid, &rule, topology);
+                        let next = grid.evolve(&rule, topology);
+                        for y in 0..height {
+                            for x in 0..width {
+                                assert_eq!(
+                                    next.get(x, y),
+                                    expected.get(x, y),
+                                    "{} {} {}x{} generation {} at ({}, {})",
+                                    rule,
+                                    topology,
+                                    width,
+                                    height,
+                                    generation,
+                                    x,
+                                    y
+                                );
+                            }
+                        }
+                        grid = next;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn equal_cells_make_equal_grids() {
+        let mut plain = Grid::new(70, 3);
+        plain.set(1, 1, CellState::Alive);
+        plain.set(65, 2, CellState::Dying(2));
+
+        // stages allocated by a cell that has since come back to life, with its stage left behind
+        let mut reused = Grid::new(70, 3);
+        reused.set(1, 1, CellState::Dying(3));
+        reused.set(1, 1, CellState::Alive);
+        reused.set(65, 2, CellState::Dying(2));
+        assert_eq!(plain, reused);
+
+        reused.set(65, 2, CellState::Dying(1));
+        assert_ne!(plain, reused);
+
+        let mut lazy = Grid::new(70, 3);
+        lazy.set(1, 1, CellState::Alive);
+        let mut eager = lazy.clone();
+        eager.set(0, 0, CellState::Dying(1));
+        eager.set(0, 0, CellState::Dead);
+        assert_eq!(lazy, eager);
+    }
+
+    #[test]
+    fn rules_round_trip() {
+        for (rule, expected) in [
+            ("B3/S23", "B3/S23"),
+            ("B36/S23", "B36/S23"),
+            ("b36 / s23", "B36/S23"),
+            ("S23/B36", "B36/S23"),
+            ("23/3", "B3/S23"),
+            ("B2/S/C3", "B2/S/C3"),
+            ("345/2/4", "B2/S345/C4"),
+            ("B3/S23/C2", "B3/S23"),
+        ] {
+            let parsed: Rule = rule.parse().unwrap();
+            assert_eq!(parsed.to_string(), expected, "{}", rule);
+            assert_eq!(expected.parse::<Rule>(), Ok(parsed));
+        }
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        for rule in [
+            "",
+            "B3",
+            "B3/S23/C3/X",
+            "B9/S23",
+            "B3/Sx",
+            "B3/B4",
+            "B3/S23/C1",
+            "life",
+        ] {
+            assert!(rule.parse::<Rule>().is_err(), "{}", rule);
+        }
+    }
+}
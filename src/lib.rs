@@ -1,12 +1,36 @@
-use std::{fs::File, str::FromStr};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use image::{
     imageops::{index_colors, BiLevel, ColorMap},
     DynamicImage, GenericImageView, ImageBuffer, Luma, Pixel, Rgb, RgbImage, Rgba, RgbaImage,
 };
-use indicatif::ProgressBar;
 use num::complex::Complex;
 
+mod colour;
+mod global_gif;
+mod pixelate;
+mod quantise;
+mod retro;
+mod threshold;
+mod upscale;
+mod webp;
+mod y4m;
+pub use colour::{linear_to_srgb, luma, oklab_to_srgb, srgb_to_linear, srgb_to_oklab, ColourSpace};
+pub use global_gif::GlobalGifSink;
+pub use pixelate::{pixelate, Fit, Pixelation, Sampling};
+pub use quantise::{Dither, Palette, Quantiser};
+pub use retro::PaletteChoice;
+pub use threshold::Threshold;
+pub use upscale::{upscale, Size, UpscaleSink};
+pub use webp::{WebpSettings, WebpSink};
+pub use y4m::Y4mSink;
+
 #[derive(Debug, Clone, Copy)]
 pub enum Mode {
     // lightest colours only
@@ -15,6 +39,10 @@ pub enum Mode {
     Dark,
     // random,
     Random,
+    // outlines only
+    Edges,
+    // opaque pixels only
+    Alpha,
 }
 
 #[derive(Debug)]
@@ -30,29 +58,34 @@ impl FromStr for Mode {
             Ok(Mode::Light)
         } else if (s == "random") | (s == "r") {
             Ok(Mode::Random)
+        } else if (s == "edges") | (s == "e") {
+            Ok(Mode::Edges)
+        } else if (s == "alpha") | (s == "a") {
+            Ok(Mode::Alpha)
         } else {
             Err(ParseModeError {})
         }
     }
 }
 
-impl ToString for Mode {
-    fn to_string(&self) -> String {
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Mode::Dark => "dark".to_string(),
-            Mode::Light => "light".to_string(),
-            Mode::Random => "random".to_string(),
+            Mode::Dark => write!(f, "dark"),
+            Mode::Light => write!(f, "light"),
+            Mode::Random => write!(f, "random"),
+            Mode::Edges => write!(f, "edges"),
+            Mode::Alpha => write!(f, "alpha"),
         }
     }
 }
 
-pub fn map_onto_whitespace(img: &DynamicImage, mode: &Mode) -> DynamicImage {
-    println!("splitting image by luminance...");
+pub fn map_onto_whitespace(img: &DynamicImage, mode: &Mode, threshold: &Threshold) -> DynamicImage {
+    eprintln!("splitting image by luminance...");
     let (w, h) = img.dimensions();
     let rgb_img = img.to_rgb8();
     let img_luma = img.to_luma8();
-    let cmap = BiLevel;
-    let palletised = index_colors(&img_luma, &cmap);
+    let palletised = threshold.split(&img_luma);
 
     //map onto black / white
 
@@ -100,8 +133,7 @@ pub fn map_onto_whitespace(img: &DynamicImage, mode: &Mode) -> DynamicImage {
         _ => {
             let mapped = ImageBuffer::from_fn(w, h, |x, y| {
                 let p = palletised.get_pixel(x, y);
-                cmap.lookup(p.0[0] as usize)
-                    .expect("indexed color out-of-range")
+                Luma([p.0[0] * 255])
             });
             DynamicImage::ImageLuma8(mapped)
         }
@@ -110,79 +142,21 @@ pub fn map_onto_whitespace(img: &DynamicImage, mode: &Mode) -> DynamicImage {
     //colour_mapped.save("output/bilevel.png");
 }
 
-pub fn pixelate(img: DynamicImage, output_width: u32) -> DynamicImage {
-    println!("Pixelating...");
-    let img = img.to_rgba8();
-
-    let (width, height) = img.dimensions();
-    println!("width = {},  output_width = {}", width, output_width);
-
-    // size of square subsections to average pixels
-    let sub_image_width = width / output_width;
-    let sub_image_area = sub_image_width * sub_image_width;
-
-    // calc height of output image
-    let output_height = height / sub_image_width;
-    let output_area = output_width * output_height;
-
-    // crop to fit n equal large pixels
-
-    //println!("Image Dimensions: {}x{}", width, height);
-    //println!("SubImage size: {}x{}", sub_image_width, sub_image_width);
-    //println!("Pixelated Image dimensions: {}x{}\n", output_width, output_height);
-
-    // create new small image of sample size
-    let mut output_img: ImageBuffer<Rgba<u8>, Vec<u8>> =
-        ImageBuffer::new(output_width, output_height);
-
-    let bar = ProgressBar::new(output_area.into());
-
-    for y in 0..output_height {
-        for x in 0..output_width {
-            // create subimage of new pixel area from original image
-            let sub_img = img
-                .view(
-                    x * sub_image_width,
-                    y * sub_image_width,
-                    sub_image_width,
-                    sub_image_width,
-                )
-                .to_image();
-
-            let (mut r_total, mut g_total, mut b_total, mut a_total) = (0u32, 0u32, 0u32, 0u32);
-            for pixel in sub_img.pixels() {
-                if let [r, g, b, a] = pixel.channels() {
-                    r_total += *r as u32;
-                    g_total += *g as u32;
-                    b_total += *b as u32;
-                    a_total += *a as u32;
-                }
-            }
-
-            let average_pixel = [
-                (r_total / sub_image_area) as u8,
-                (g_total / sub_image_area) as u8,
-                (b_total / sub_image_area) as u8,
-                (a_total / sub_image_area) as u8,
-            ];
-            //println!("avg rgb: {:?}", average_pixel);
-
-            // paint average pixel of subimage into subimage location
-            output_img.put_pixel(x, y, Rgba(average_pixel));
-
-            bar.inc(1)
-        }
-    }
-
-    //println!("{:?}", output_img.dimensions());
-    DynamicImage::ImageRgba8(output_img)
-}
-
 pub fn create_background(
     foreground: ImageBuffer<Rgba<u8>, Vec<u8>>,
     colour: &BackgroundColour,
+    space: ColourSpace,
 ) -> DynamicImage {
     let mut composite: ImageBuffer<Rgba<u8>, Vec<u8>> = foreground;
+    // fade from the background to each pixel by its alpha, in the space asked for
+    if space != ColourSpace::Srgb {
+        let background = colour.rgb();
+        for pix in composite.pixels_mut() {
+            let [r, g, b] = space.mix(background, [pix[0], pix[1], pix[2]], pix[3] as f64 / 255.0);
+            *pix = Rgba([r, g, b, 255]);
+        }
+        return DynamicImage::from(composite);
+    }
     match *colour {
         BackgroundColour::Black => {
             for (_, _, pix) in composite.enumerate_pixels_mut() {
@@ -226,36 +200,256 @@ fn step_universe(universe: Universe, (width, height): (u32, u32)) {
 }
 */
 
-pub fn make_gif(slides: Vec<RgbImage>, speed: bool, file_path: &str) {
-    println!("creating gif at {}.gif", file_path);
+fn write_gif_frame<W: Write>(
+    encoder: &mut gif::Encoder<W>,
+    img: RgbImage,
+    speed: i32,
+    delay_ms: u16,
+) {
+    let (width, height) = img.dimensions();
+    let pixels = img.into_raw();
+    let mut frame = gif::Frame::from_rgb_speed(width as u16, height as u16, &pixels, speed);
+    frame.delay = gif_delay(delay_ms);
 
-    let mut image = File::create(format!("{}.gif", file_path)).expect("couldn't save gif to path:");
-    let (width, height) = slides[0].dimensions();
-    let mut encoder = gif::Encoder::new(&mut image, width as u16, height as u16, &[]).unwrap();
+    // Write frame to file
+    encoder.write_frame(&frame).unwrap();
+}
 
-    let bar = ProgressBar::new(slides.len() as u64);
+// gif delays are in hundredths of a second. worked out wider so the longest delays don't overflow
+fn gif_delay(delay_ms: u16) -> u16 {
+    ((delay_ms as u32 + 5) / 10) as u16
+}
 
-    if speed {
-        for img in slides {
-            let pixels = img.into_raw();
-            let frame = gif::Frame::from_rgb_speed(width as u16, height as u16, &*pixels, 10);
+// without a repeat extension a gif plays once, the count is of extra plays after that
+fn set_gif_repeat<W: Write>(encoder: &mut gif::Encoder<W>, loops: Loop) {
+    match loops {
+        Loop::Infinite => encoder.set_repeat(gif::Repeat::Infinite).unwrap(),
+        Loop::Times(1) => {}
+        Loop::Times(times) => encoder.set_repeat(gif::Repeat::Finite(times - 1)).unwrap(),
+    }
+}
+
+// how many times an animation plays through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Loop {
+    Infinite,
+    Times(u16),
+}
 
-            // Write frame to file
-            encoder.write_frame(&frame).unwrap();
-            bar.inc(1);
+impl FromStr for Loop {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_whitespace().collect::<String>().to_lowercase();
+        match s.as_str() {
+            "infinite" | "forever" | "i" => Ok(Loop::Infinite),
+            "once" | "o" => Ok(Loop::Times(1)),
+            _ => match s.parse::<u16>() {
+                Ok(times) if times > 0 => Ok(Loop::Times(times)),
+                _ => Err(format!(
+                    "loop '{}' should be \"infinite\", \"once\" or a number of plays",
+                    s
+                )),
+            },
         }
-    } else {
-        for img in slides {
-            let pixels = img.into_raw();
-            let frame = gif::Frame::from_rgb(width as u16, height as u16, &*pixels);
+    }
+}
 
-            // Write frame to file
-            encoder.write_frame(&frame).unwrap();
-            bar.inc(1);
+// how long slides stay on screen, in milliseconds, and how the animation repeats
+#[derive(Debug, Clone, Copy)]
+pub struct Timing {
+    // each generation
+    pub delay_ms: u16,
+    // the starting image, before life begins
+    pub intro_ms: u16,
+    // the last generation
+    pub final_ms: u16,
+    pub loops: Loop,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        // gifs without a delay play at around 10fps, match that
+        Timing {
+            delay_ms: 100,
+            intro_ms: 500,
+            final_ms: 100,
+            loops: Loop::Infinite,
         }
     }
 }
 
+// takes each slide as soon as it has been rendered, so memory use doesn't grow with the
+// number of generations
+pub trait FrameSink {
+    // delay_ms is how long this slide stays up before the next
+    fn write_frame(&mut self, slide: &RgbaImage, delay_ms: u16);
+
+    // called once after the last slide, for formats that can only be written out at the end
+    fn finish(&mut self) {}
+}
+
+// flattens slides onto the background colour and encodes them straight into a gif
+pub struct GifSink {
+    encoder: gif::Encoder<File>,
+    background: BackgroundColour,
+    space: ColourSpace,
+    quantiser: Quantiser,
+    dither: Dither,
+}
+
+impl GifSink {
+    pub fn new(
+        file_path: &str,
+        (width, height): (u32, u32),
+        background: BackgroundColour,
+        space: ColourSpace,
+        loops: Loop,
+        quantiser: Quantiser,
+        dither: Dither,
+    ) -> GifSink {
+        eprintln!("creating gif at {}", file_path);
+
+        let image = File::create(file_path).expect("couldn't save gif to path:");
+        let mut encoder = gif::Encoder::new(image, width as u16, height as u16, &[]).unwrap();
+        set_gif_repeat(&mut encoder, loops);
+        GifSink {
+            encoder,
+            background,
+            space,
+            quantiser,
+            dither,
+        }
+    }
+}
+
+impl FrameSink for GifSink {
+    fn write_frame(&mut self, slide: &RgbaImage, delay_ms: u16) {
+        let img = create_background(slide.clone(), &self.background, self.space).to_rgb8();
+        // the gif crate's own neuquant handles the undithered case
+        if let (Quantiser::NeuQuant(speed), Dither::None) = (self.quantiser, self.dither) {
+            write_gif_frame(&mut self.encoder, img, speed, delay_ms);
+            return;
+        }
+
+        let pixels: Vec<[u8; 3]> = img.pixels().map(|pix| pix.0).collect();
+        let mut palette = Palette::build(&self.quantiser, &pixels, 256);
+        let indices = palette.map(&img, &self.dither);
+        let (width, height) = img.dimensions();
+        let mut frame = gif::Frame::from_palette_pixels(
+            width as u16,
+            height as u16,
+            &indices,
+            &palette.rgb(),
+            None,
+        );
+        frame.delay = gif_delay(delay_ms);
+        self.encoder.write_frame(&frame).unwrap();
+    }
+}
+
+// animated png keeping full 24-bit colour and the slides' own alpha, no background is applied
+pub struct ApngSink {
+    // taken by finish(), which writes the closing chunk
+    writer: Option<png::Writer<BufWriter<File>>>,
+}
+
+impl ApngSink {
+    // apng needs the number of frames up front
+    pub fn new(file_path: &str, (width, height): (u32, u32), frames: u32, loops: Loop) -> ApngSink {
+        eprintln!("creating apng at {}", file_path);
+
+        let image = File::create(file_path).expect("couldn't save apng to path:");
+        let mut encoder = png::Encoder::new(BufWriter::new(image), width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let plays = match loops {
+            Loop::Infinite => 0,
+            Loop::Times(times) => times as u32,
+        };
+        encoder.set_animated(frames, plays).unwrap();
+        // each slide replaces the last rather than drawing over it
+        encoder.set_blend_op(png::BlendOp::Source).unwrap();
+        let writer = encoder.write_header().expect("couldn't write apng header");
+        ApngSink {
+            writer: Some(writer),
+        }
+    }
+}
+
+impl FrameSink for ApngSink {
+    fn write_frame(&mut self, slide: &RgbaImage, delay_ms: u16) {
+        let writer = self.writer.as_mut().expect("apng already finished");
+        writer.set_frame_delay(delay_ms, 1000).unwrap();
+        writer.write_image_data(slide.as_raw()).unwrap();
+    }
+
+    fn finish(&mut self) {
+        // dropping the writer would end the file too, but quietly lose any error
+        if let Some(writer) = self.writer.take() {
+            writer.finish().expect("couldn't finish writing apng");
+        }
+    }
+}
+
+// writes each slide flattened onto the background as 000.png, 001.png, ... in a directory,
+// plus a manifest.json describing the sequence for video tools
+pub struct FramesSink {
+    directory: PathBuf,
+    background: BackgroundColour,
+    space: ColourSpace,
+    dimensions: (u32, u32),
+    digits: usize,
+    delays_ms: Vec<u16>,
+}
+
+impl FramesSink {
+    pub fn new(
+        directory: &str,
+        dimensions: (u32, u32),
+        background: BackgroundColour,
+        space: ColourSpace,
+        frames: u32,
+    ) -> FramesSink {
+        eprintln!("writing frames to {}", directory);
+
+        fs::create_dir_all(directory).expect("couldn't create frames directory");
+        FramesSink {
+            directory: PathBuf::from(directory),
+            background,
+            space,
+            dimensions,
+            digits: frames.saturating_sub(1).to_string().len(),
+            delays_ms: Vec::new(),
+        }
+    }
+}
+
+impl FrameSink for FramesSink {
+    fn write_frame(&mut self, slide: &RgbaImage, delay_ms: u16) {
+        let img = create_background(slide.clone(), &self.background, self.space).to_rgb8();
+        let name = format!("{:0width$}.png", self.delays_ms.len(), width = self.digits);
+        img.save(self.directory.join(name))
+            .expect("couldn't save frame to path:");
+        self.delays_ms.push(delay_ms);
+    }
+
+    fn finish(&mut self) {
+        let (width, height) = self.dimensions;
+        let delays = self
+            .delays_ms
+            .iter()
+            .map(|delay| delay.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        let manifest = format!(
+            "{{\n  \"width\": {},\n  \"height\": {},\n  \"frames\": {},\n  \"delays_ms\": [{}],\n  \"pattern\": \"%0{}d.png\"\n}}\n",
+            width, height, self.delays_ms.len(), delays, self.digits
+        );
+        fs::write(self.directory.join("manifest.json"), manifest)
+            .expect("couldn't save frame manifest:");
+    }
+}
+
 pub fn split() {
     let (w, h) = (16, 16);
     // Create an image with a smooth horizontal gradient from black (0) to white (255).
@@ -384,6 +578,8 @@ pub fn cross() {
     //img.save("output/test.png");
 }
 
+// plain % keeps this building on compilers older than is_multiple_of (rust 1.87)
+#[allow(clippy::manual_is_multiple_of)]
 pub fn fit(width: u32, height: u32) -> Option<Vec<(u32, u32)>> {
     // find if you can evenly split image into squares
     // find common multiples of width and height
@@ -405,12 +601,77 @@ pub fn fit(width: u32, height: u32) -> Option<Vec<(u32, u32)>> {
     }
 }
 
+// animation file formats pixelator can write
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Gif,
+    Apng,
+    Webp,
+    // a directory of numbered pngs
+    Frames,
+    // raw video for piping into encoders
+    Y4m,
+}
+
+impl Format {
+    // where output named after stem goes in this format
+    pub fn file_path(&self, stem: &str) -> String {
+        match self {
+            Format::Gif => format!("{}.gif", stem),
+            Format::Apng => format!("{}.png", stem),
+            Format::Webp => format!("{}.webp", stem),
+            Format::Frames => stem.to_string(),
+            Format::Y4m => format!("{}.y4m", stem),
+        }
+    }
+
+    // guess the format from a file path's extension
+    pub fn from_path(path: &str) -> Option<Format> {
+        let extension = Path::new(path).extension()?.to_str()?;
+        match extension.to_lowercase().as_str() {
+            "gif" => Some(Format::Gif),
+            "png" | "apng" => Some(Format::Apng),
+            "webp" => Some(Format::Webp),
+            "y4m" => Some(Format::Y4m),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_whitespace().collect::<String>().to_lowercase();
+        match s.as_str() {
+            "gif" => Ok(Format::Gif),
+            "apng" | "png" => Ok(Format::Apng),
+            "webp" => Ok(Format::Webp),
+            "frames" => Ok(Format::Frames),
+            "y4m" => Ok(Format::Y4m),
+            _ => Err(format!("unknown output format '{}'", s)),
+        }
+    }
+}
+
+#[derive(Default)]
 pub enum BackgroundColour {
+    #[default]
     Black,
     White,
     Custom((u8, u8, u8)),
 }
 
+impl BackgroundColour {
+    pub fn rgb(&self) -> [u8; 3] {
+        match *self {
+            BackgroundColour::Black => [0, 0, 0],
+            BackgroundColour::White => [255, 255, 255],
+            BackgroundColour::Custom((r, g, b)) => [r, g, b],
+        }
+    }
+}
+
 // todo: implement better errors
 impl FromStr for BackgroundColour {
     type Err = String;
@@ -428,24 +689,3 @@ impl FromStr for BackgroundColour {
         }
     }
 }
-
-impl Default for BackgroundColour {
-    fn default() -> Self {
-        BackgroundColour::Black
-    }
-}
-
-pub fn background_for_slides(
-    slides: Vec<ImageBuffer<Rgba<u8>, Vec<u8>>>,
-    colour: BackgroundColour,
-) -> Vec<ImageBuffer<Rgb<u8>, Vec<u8>>> {
-    let mut blended: Vec<RgbImage> = Vec::new();
-    println!("applying background");
-    let bar = ProgressBar::new(slides.len() as u64);
-    for s in slides {
-        let new_img = create_background(s, &colour).to_rgb8();
-        blended.push(new_img);
-        bar.inc(1);
-    }
-    blended
-}
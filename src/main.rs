@@ -1,9 +1,19 @@
-use std::{fs, str::FromStr};
+use std::{fs, path::Path, str::FromStr};
 
-use pixelator::{background_for_slides, make_gif, pixelate, BackgroundColour, Mode};
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+
+use pixelator::{
+    pixelate, ApngSink, BackgroundColour, ColourSpace, Dither, Fit, Format, FrameSink, FramesSink,
+    GifSink, GlobalGifSink, Loop, Mode, PaletteChoice, Pixelation, Quantiser, Sampling, Size,
+    Threshold, Timing, UpscaleSink, WebpSettings, WebpSink, Y4mSink,
+};
 
 mod conway;
-use conway::begin_life;
+mod grid;
+use conway::{
+    begin_life, DyingColour, Inheritance, Physics, Rule, Seeding, Topology, Weighting,
+    RANDOM_DENSITY,
+};
 
 use clap::Parser;
 
@@ -15,7 +25,8 @@ struct Args {
     path: String,
 
     #[arg(short, long)]
-    /// Output directory. ./output/ if none given.
+    /// Output directory. ./output/ if none given. A path ending in .gif, .png, .apng, .webp or .y4m is used as the output file,
+    /// "-" streams y4m video to stdout.
     output_directory: Option<String>,
 
     #[arg(short, long, default_value_t = 100)]
@@ -31,17 +42,160 @@ struct Args {
     background: String,
 
     #[arg(short, long)]
-    /// Select "dark" or "light" mode for colour mapping options. Default = "random"
+    /// Select "dark", "light", "edges" (outlines) or "alpha" (opaque pixels) mode for colour mapping options.
+    /// Default = "random"
     mode: Option<String>,
 
-    /// Set true for faster lossy gif rendering
+    /// Luminance 0-255 splitting light from dark in light and dark modes, or "otsu", "mean",
+    /// or a percentile like "p30" (30% of pixels count as dark). In edges mode it splits edge strength instead.
+    /// Default is 127, or "otsu" in edges mode
+    #[arg(long)]
+    threshold: Option<String>,
+
+    /// Dither brightness into scattered live cells in light and dark modes instead of solid regions:
+    /// "none", "floyd-steinberg", "atkinson" or "bayer"
+    #[arg(long, default_value = "none")]
+    seed_dither: String,
+
+    /// Share 0-1 of cells alive at the start of random mode. Default is about 0.63
+    #[arg(long)]
+    density: Option<f64>,
+
+    /// Make cells more likely to start alive in random mode where pixels are: "uniform", "luma" (bright),
+    /// "dark" or "alpha" (opaque)
+    #[arg(long, default_value = "uniform")]
+    weight: String,
+
+    /// Image whose bright pixels pick the starting live cells, whatever the mode. Stretched to fit the output
+    #[arg(long)]
+    mask: Option<String>,
+
+    /// Run a separate game of life on each of the red, green and blue channels, seeded from its own channel
+    #[arg(long, default_value_t = false, conflicts_with = "palette")]
+    split_channels: bool,
+
+    /// Seed for random mode. The same seed and options always give the same output. Random if not given
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Set true for faster lossy gif rendering. Same as --quantiser neuquant:10
     #[arg(short, long, default_value_t = false)]
     speed: bool,
 
+    /// Gif palette quantiser: "neuquant" (optionally with a speed, "neuquant:1" best to "neuquant:30" fastest),
+    /// "median-cut" or "k-means"
+    #[arg(long)]
+    quantiser: Option<String>,
+
+    /// Gif dithering: "none", "floyd-steinberg", "atkinson" or "bayer"
+    #[arg(long, default_value = "none")]
+    dither: String,
+
     /// Output pixel width dimension using pixelation effect. Default is original image size.
     /// Smaller picture sizes result in exponentially faster renders.
-    #[arg(short, long)]
+    #[arg(short, long, conflicts_with_all = ["height", "cell_size"])]
     width: Option<u32>,
+
+    /// Output pixel height dimension using pixelation effect, instead of --width
+    #[arg(long, conflicts_with = "cell_size")]
+    height: Option<u32>,
+
+    /// Pixelate into cells this many pixels square, instead of --width
+    #[arg(long)]
+    cell_size: Option<u32>,
+
+    /// When the image doesn't divide evenly into cells: "crop" the leftover edges, "pad" with transparency,
+    /// or "resample" sharing edge pixels between cells
+    #[arg(long, default_value = "resample")]
+    fit: String,
+
+    /// How each pixelated cell's colour is picked: "mean", "median", "mode" (most common colour), "centre" pixel,
+    /// "luma" (mean favouring bright pixels) or "linear" (mean in linear light)
+    #[arg(long, default_value = "mean")]
+    sampling: String,
+
+    /// Limit colours to a palette after pixelating: "pico8", "gameboy", "nes", "cga", a .hex or .gpl file,
+    /// or "k-means:N" for N colours picked from the image. Births stay on the palette too
+    #[arg(long)]
+    palette: Option<String>,
+
+    /// Blow each output frame up by this many times with crisp nearest-neighbour blocks
+    #[arg(long, conflicts_with = "output_size")]
+    scale: Option<u32>,
+
+    /// Blow each output frame up to "WxH", or "W" / "xH" keeping the aspect ratio, with nearest-neighbour blocks
+    #[arg(long)]
+    output_size: Option<String>,
+
+    /// Draw lines of this colour between cells upscaled to 2 pixels or more: "white"/"w", "black"/"b", "#RRGGBB"
+    #[arg(long)]
+    grid_lines: Option<String>,
+
+    /// Life-like rule in B/S notation, e.g. "B3/S23" (Conway), "B36/S23" (HighLife), "B2/S" (Seeds).
+    /// Add a state count for Generations rules, e.g. "B2/S/C3" (Brian's Brain), "B2/S345/C4" (Star Wars)
+    #[arg(short, long, default_value = "B3/S23")]
+    rule: String,
+
+    /// Colour of newborn cells from their parents: "blend" (each laid over the last), "mean", "majority",
+    /// "random" parent, "oklab" (mean in a perceptual colour space) or "mutate[:N]" (mean nudged by up to N, default 16)
+    #[arg(long, default_value = "blend")]
+    birth: String,
+
+    /// Colour treatment of dying cells in Generations rules: "fade", "darken", "desaturate" or a "#RRGGBB" tint
+    #[arg(long, default_value = "fade")]
+    dying: String,
+
+    /// Where births, dying cells and the background mix colours: "srgb" (the raw bytes, mixes go dark),
+    /// "linear" (as light mixes) or "oklab" (perceptually even blends and fades)
+    #[arg(long, default_value = "srgb")]
+    colour_space: String,
+
+    /// What lies beyond the image edges: "dead", "alive", "torus", "klein" or "mirror"
+    #[arg(short, long, default_value = "dead")]
+    topology: String,
+
+    /// Output format: "gif", "apng" or "webp" (both keep full colour and transparency, ignoring --background),
+    /// "frames" for a directory of numbered pngs or "y4m" raw video.
+    /// Guessed from the output file extension if not given, otherwise gif
+    #[arg(short, long)]
+    format: Option<String>,
+
+    /// Share one palette between every gif frame and only store the pixels that change.
+    /// Stops colours flickering and gives much smaller files, but holds every frame in memory
+    #[arg(long, default_value_t = false)]
+    global_palette: bool,
+
+    /// Lossless webp compression
+    #[arg(long, default_value_t = false)]
+    lossless: bool,
+
+    /// Webp quality 0-100, or compression effort when lossless
+    #[arg(long, default_value_t = 75.0)]
+    webp_quality: f32,
+
+    /// Webp method 0-6, slower methods give smaller files
+    #[arg(long, default_value_t = 4)]
+    webp_method: i32,
+
+    /// Frame rate of y4m video
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    fps: u32,
+
+    /// Milliseconds each generation stays on screen. Gif rounds to the nearest 10ms
+    #[arg(long, default_value_t = 100)]
+    delay: u16,
+
+    /// Milliseconds the starting image is held before life begins
+    #[arg(long, default_value_t = 500)]
+    intro_delay: u16,
+
+    /// Milliseconds the last generation is held before the animation loops. Default is --delay
+    #[arg(long)]
+    final_delay: Option<u16>,
+
+    /// How many times the animation plays: "infinite", "once" or a number of plays
+    #[arg(long = "loop", default_value = "infinite")]
+    loops: String,
 }
 
 fn main() {
@@ -52,6 +206,7 @@ fn main() {
     let decay = args.decay;
 
     let background: BackgroundColour = args.background.parse().unwrap();
+    let colour_space: ColourSpace = args.colour_space.parse().expect("not a valid colour space");
 
     // parse mode input string
     let mode = match args.mode {
@@ -60,48 +215,252 @@ fn main() {
         None => Mode::Random,
     };
 
-    let speed = args.speed;
+    let mut seeding = Seeding {
+        threshold: match args.threshold {
+            Some(threshold) => threshold.parse().expect("not a valid threshold"),
+            None if matches!(mode, Mode::Edges) => Threshold::Otsu,
+            None => Threshold::default(),
+        },
+        dither: args
+            .seed_dither
+            .parse::<Dither>()
+            .expect("not a valid seed dither"),
+        density: args.density.unwrap_or(RANDOM_DENSITY),
+        weighting: args
+            .weight
+            .parse::<Weighting>()
+            .expect("not a valid weighting"),
+        seed: args.seed.unwrap_or_else(rand::random),
+        mask: None,
+        split_channels: args.split_channels,
+    };
+    assert!(
+        (0.0..=1.0).contains(&seeding.density),
+        "density should be 0-1"
+    );
+    eprintln!("seed = {}", seeding.seed);
+
+    let rule: Rule = args.rule.parse().expect("not a valid rule");
+    let dying: DyingColour = args.dying.parse().expect("not a valid dying colour");
+    let topology: Topology = args.topology.parse().expect("not a valid topology");
+
+    let timing = Timing {
+        delay_ms: args.delay,
+        intro_ms: args.intro_delay,
+        final_ms: args.final_delay.unwrap_or(args.delay),
+        loops: args.loops.parse::<Loop>().expect("not a valid loop count"),
+    };
+
+    let quantiser = match args.quantiser {
+        Some(quantiser) => quantiser.parse().expect("not a valid quantiser"),
+        None if args.speed => Quantiser::NeuQuant(10),
+        None => Quantiser::default(),
+    };
+    let dither: Dither = args.dither.parse().expect("not a valid dither");
     // strip filename from path
     let s = image_path.split(['/', '\\', '.']).collect::<Vec<&str>>();
     // take second to last as last element is format
     let file_name = s[s.len() - 2];
 
+    let pixelation = match (args.width, args.height, args.cell_size) {
+        (Some(width), _, _) => Some(Pixelation::Width(width)),
+        (_, Some(height), _) => Some(Pixelation::Height(height)),
+        (_, _, Some(size)) => Some(Pixelation::CellSize(size)),
+        _ => None,
+    };
+    if let Some(Pixelation::Width(0) | Pixelation::Height(0) | Pixelation::CellSize(0)) = pixelation
+    {
+        panic!("pixelation size should be at least 1");
+    }
+    let fit: Fit = args.fit.parse().expect("not a valid fit");
+    let sampling: Sampling = args.sampling.parse().expect("not a valid sampling");
+
     //parse output width as string
-    let output_width = match args.width {
-        Some(width) => width.to_string(),
+    let output_width = match pixelation {
+        Some(Pixelation::Width(width)) => width.to_string(),
+        Some(Pixelation::Height(height)) => format!("h{}", height),
+        Some(Pixelation::CellSize(size)) => format!("c{}", size),
         None => "OG".to_string(),
     };
 
     // create new filename
-    let new_file_name = format!(
+    let mut new_file_name = format!(
         "/{}x{}_{}_{}_{}",
-        file_name,
-        output_width,
-        mode.to_string(),
-        generations,
-        decay
+        file_name, output_width, mode, generations, decay
     );
+    // tag non-conway renders so runs under different rules don't overwrite each other
+    if rule != Rule::default() {
+        new_file_name += &format!("_{}", rule.to_string().replace('/', ""));
+    }
+    if topology != Topology::default() {
+        new_file_name += &format!("_{}", topology);
+    }
+    if colour_space != ColourSpace::default() {
+        new_file_name += &format!("_{}", colour_space);
+    }
+    if args.mask.is_some() {
+        new_file_name += "_mask";
+    }
+    if args.split_channels {
+        new_file_name += "_rgb";
+    }
+
+    let format = args
+        .format
+        .map(|f| f.parse::<Format>().expect("not a valid format"));
 
     // create output path if it doesnt already exist
-    let output_path = if let Some(path) = args.output_directory {
-        create_directory(&path).expect("couldn't create path");
-        path + &new_file_name
-    } else {
-        create_directory("output").expect("couldn't create path");
-        "output".to_owned() + &new_file_name
+    let (output_path, format) = match args.output_directory {
+        // stdout
+        Some(path) if path == "-" => {
+            let format = format.unwrap_or(Format::Y4m);
+            assert!(format == Format::Y4m, "only y4m can be streamed to stdout");
+            (path, format)
+        }
+        // an explicit file to write to
+        Some(path) if Format::from_path(&path).is_some() => {
+            if let Some(parent) = Path::new(&path).parent() {
+                create_directory(&parent.to_string_lossy()).expect("couldn't create path");
+            }
+            let format = format.or(Format::from_path(&path)).unwrap_or_default();
+            (path, format)
+        }
+        Some(path) => {
+            create_directory(&path).expect("couldn't create path");
+            let format = format.unwrap_or_default();
+            (format.file_path(&(path + &new_file_name)), format)
+        }
+        None => {
+            create_directory("output").expect("couldn't create path");
+            let format = format.unwrap_or_default();
+            (
+                format.file_path(&("output".to_owned() + &new_file_name)),
+                format,
+            )
+        }
     };
 
-    println!("loading image...");
+    eprintln!("loading image...");
     let mut img = image::open(image_path).expect("failed to load image");
 
-    if let Some(width) = args.width {
-        img = pixelate(img, width);
+    if let Some(pixelation) = pixelation {
+        img = pixelate(img, pixelation, fit, sampling);
     }
 
-    let slides = begin_life(img, generations, decay, &mode);
-    let blended = background_for_slides(slides, background);
+    let (width, height) = img.dimensions();
+    seeding.mask = args.mask.map(|path| {
+        let mask = image::open(path).expect("failed to load mask");
+        mask.resize_exact(width, height, FilterType::Triangle)
+            .to_luma8()
+    });
 
-    make_gif(blended, speed, &output_path);
+    let output_dimensions = match (args.scale, args.output_size) {
+        (Some(scale), _) => {
+            assert!(scale > 0, "scale should be at least 1");
+            (width * scale, height * scale)
+        }
+        (None, Some(size)) => size
+            .parse::<Size>()
+            .expect("not a valid output size")
+            .resolve((width, height)),
+        (None, None) => (width, height),
+    };
+    let grid_lines = args.grid_lines.map(|colour| {
+        colour
+            .parse::<BackgroundColour>()
+            .expect("not a valid grid line colour")
+    });
+
+    let palette = args.palette.map(|choice| {
+        let choice: PaletteChoice = choice.parse().expect("not a valid palette");
+        let mut rgba = img.to_rgba8();
+        let mut palette = choice.palette(&rgba).expect("couldn't load palette");
+        eprintln!("mapping onto {} colours...", palette.len());
+        palette.snap(&mut rgba);
+        img = DynamicImage::ImageRgba8(rgba);
+        palette
+    });
+
+    let physics = Physics {
+        rule,
+        topology,
+        dying,
+        decay,
+        palette,
+        inheritance: args
+            .birth
+            .parse::<Inheritance>()
+            .expect("not a valid birth colour"),
+        seed: seeding.seed,
+        colour_space,
+    };
+    let mut sink: Box<dyn FrameSink> = match format {
+        Format::Gif if args.global_palette => Box::new(GlobalGifSink::new(
+            &output_path,
+            output_dimensions,
+            background,
+            colour_space,
+            timing.loops,
+            quantiser,
+            dither,
+        )),
+        Format::Gif => Box::new(GifSink::new(
+            &output_path,
+            output_dimensions,
+            background,
+            colour_space,
+            timing.loops,
+            quantiser,
+            dither,
+        )),
+        Format::Apng => Box::new(ApngSink::new(
+            &output_path,
+            output_dimensions,
+            (1 + generations) as u32,
+            timing.loops,
+        )),
+        Format::Webp => Box::new(WebpSink::new(
+            &output_path,
+            output_dimensions,
+            WebpSettings {
+                lossless: args.lossless,
+                quality: args.webp_quality,
+                method: args.webp_method,
+            },
+            timing.loops,
+        )),
+        Format::Frames => Box::new(FramesSink::new(
+            &output_path,
+            output_dimensions,
+            background,
+            colour_space,
+            (1 + generations) as u32,
+        )),
+        Format::Y4m => Box::new(Y4mSink::new(
+            &output_path,
+            output_dimensions,
+            background,
+            colour_space,
+            args.fps,
+        )),
+    };
+    if output_dimensions != (width, height) {
+        sink = Box::new(UpscaleSink::new(sink, output_dimensions, grid_lines));
+    } else if grid_lines.is_some() {
+        eprintln!(
+            "grid lines need --scale or --output-size to make room for them, leaving them out"
+        );
+    }
+    begin_life(
+        img,
+        generations,
+        &mode,
+        &seeding,
+        &physics,
+        &timing,
+        sink.as_mut(),
+    );
+    sink.finish();
 }
 
 fn create_directory(path: &str) -> std::io::Result<()> {
@@ -0,0 +1,428 @@
+use std::{collections::HashMap, str::FromStr};
+
+use image::{DynamicImage, Rgba, RgbaImage};
+use indicatif::ProgressBar;
+
+use crate::colour::{linear_to_srgb, luma, srgb_to_linear};
+
+// how big the pixelated image should be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pixelation {
+    // this many cells across
+    Width(u32),
+    // this many cells down
+    Height(u32),
+    // cells this many pixels square
+    CellSize(u32),
+}
+
+// what to do when the image doesn't divide evenly into cells
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fit {
+    // whole blocks of pixels, trimming the leftover evenly from each edge
+    Crop,
+    // whole blocks of pixels, growing the image with transparency evenly at each edge
+    Pad,
+    // fractional blocks, pixels straddling a cell edge shared between cells by area
+    #[default]
+    Resample,
+}
+
+impl FromStr for Fit {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_whitespace().collect::<String>().to_lowercase();
+        match s.as_str() {
+            "crop" | "c" => Ok(Fit::Crop),
+            "pad" | "p" => Ok(Fit::Pad),
+            "resample" | "area" | "r" => Ok(Fit::Resample),
+            _ => Err(format!(
+                "fit '{}' should be \"crop\", \"pad\" or \"resample\"",
+                s
+            )),
+        }
+    }
+}
+
+// how each cell's colour is picked from the pixels it covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Sampling {
+    // average of the srgb values
+    #[default]
+    Mean,
+    // middle value of each channel, ignores stray specks
+    Median,
+    // most common colour, keeps flat pixel art colours exact
+    Mode,
+    // the pixel at the middle of the cell
+    Centre,
+    // average favouring brighter pixels, so highlights survive
+    Luma,
+    // average in linear light, so dark and bright detail don't turn muddy
+    Linear,
+}
+
+impl FromStr for Sampling {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_whitespace().collect::<String>().to_lowercase();
+        match s.as_str() {
+            "mean" | "average" => Ok(Sampling::Mean),
+            "median" => Ok(Sampling::Median),
+            "mode" | "majority" => Ok(Sampling::Mode),
+            "centre" | "center" => Ok(Sampling::Centre),
+            "luma" | "luminance" => Ok(Sampling::Luma),
+            "linear" => Ok(Sampling::Linear),
+            _ => Err(format!(
+                "sampling '{}' should be \"mean\", \"median\", \"mode\", \"centre\", \"luma\" or \"linear\"",
+                s
+            )),
+        }
+    }
+}
+
+// cells across and down, and the size and top left of the cell grid in image pixels
+struct Layout {
+    cells: (u32, u32),
+    cell_size: (f64, f64),
+    origin: (f64, f64),
+}
+
+impl Layout {
+    fn new((width, height): (u32, u32), pixelation: Pixelation, fit: Fit) -> Layout {
+        // square cells, as many image pixels across as it takes to give the size asked for
+        let block = match pixelation {
+            Pixelation::Width(cells) => width as f64 / cells as f64,
+            Pixelation::Height(cells) => height as f64 / cells as f64,
+            Pixelation::CellSize(size) => size as f64,
+        };
+        // crop and pad only deal in whole pixels, and no cell is smaller than a pixel
+        let block = match fit {
+            Fit::Crop => block.floor().max(1.0),
+            Fit::Pad => block.ceil().max(1.0),
+            Fit::Resample => block.max(1.0),
+        };
+        let count = |length: u32| {
+            let cells = length as f64 / block;
+            match fit {
+                Fit::Crop => cells.floor().max(1.0) as u32,
+                Fit::Pad => cells.ceil() as u32,
+                Fit::Resample => cells.round().max(1.0) as u32,
+            }
+        };
+        let mut cells = (count(width), count(height));
+
+        // the side asked for gets exactly that many cells, if there are pixels enough to crop
+        let asked = match pixelation {
+            Pixelation::Width(wanted) => Some((&mut cells.0, &mut cells.1, wanted, width, height)),
+            Pixelation::Height(wanted) => Some((&mut cells.1, &mut cells.0, wanted, height, width)),
+            Pixelation::CellSize(_) => None,
+        };
+        if let Some((along, across, wanted, along_length, across_length)) = asked {
+            // blocks can't shrink below a pixel, so crop runs out of whole blocks, pad would only
+            // be adding blank cells and resampling would blow the image up rather than pixelate it
+            let short = match fit {
+                Fit::Crop => (wanted > *along).then_some("crop"),
+                Fit::Pad => (wanted > along_length).then_some("pad"),
+                Fit::Resample => (wanted > along_length).then_some("resample"),
+            };
+            if let Some(fitting) = short {
+                eprintln!(
+                    "not enough pixels to {} into {} cells, using {}",
+                    fitting, wanted, along
+                );
+            } else {
+                *along = wanted;
+            }
+            // crop or pad the other side by the same share so the picture keeps its shape
+            let keep = (*along as f64 * across_length as f64 / along_length as f64).round();
+            let keep = keep.max(1.0) as u32;
+            match fit {
+                Fit::Crop => *across = (*across).min(keep),
+                Fit::Pad => *across = (*across).max(keep),
+                Fit::Resample => {}
+            }
+        }
+
+        let cell_size = match fit {
+            // resampled cells stretch a touch so the grid covers the image exactly
+            Fit::Resample => (
+                width as f64 / cells.0 as f64,
+                height as f64 / cells.1 as f64,
+            ),
+            Fit::Crop | Fit::Pad => (block, block),
+        };
+
+        // centre the grid, negative when padding. whole blocks stay lined up with whole pixels
+        let offset = |length: u32, cells: u32, size: f64| {
+            let offset = (length as f64 - cells as f64 * size) / 2.0;
+            match fit {
+                Fit::Resample => offset,
+                Fit::Crop | Fit::Pad => offset.floor(),
+            }
+        };
+
+        Layout {
+            cells,
+            cell_size,
+            origin: (
+                offset(width, cells.0, cell_size.0),
+                offset(height, cells.1, cell_size.1),
+            ),
+        }
+    }
+}
+
+// anything outside the image counts as transparent
+fn pixel_at(img: &RgbaImage, x: i64, y: i64) -> Rgba<u8> {
+    if (0..img.width() as i64).contains(&x) && (0..img.height() as i64).contains(&y) {
+        *img.get_pixel(x as u32, y as u32)
+    } else {
+        Rgba([0, 0, 0, 0])
+    }
+}
+
+// pixels of an image overlapping [x0, x1) x [y0, y1), with how much of each is covered
+fn covered(img: &RgbaImage, (x0, x1): (f64, f64), (y0, y1): (f64, f64)) -> Vec<(Rgba<u8>, f64)> {
+    let mut pixels = Vec::new();
+    for y in y0.floor() as i64..y1.ceil() as i64 {
+        let height = (y1.min(y as f64 + 1.0) - y0.max(y as f64)).max(0.0);
+        for x in x0.floor() as i64..x1.ceil() as i64 {
+            let width = (x1.min(x as f64 + 1.0) - x0.max(x as f64)).max(0.0);
+            pixels.push((pixel_at(img, x, y), width * height));
+        }
+    }
+    pixels
+}
+
+// average colour, weighting each pixel by its alpha so transparent pixels don't darken it.
+// brightness scales each pixel's share, decode / encode move the average into another space
+fn mean(
+    pixels: &[(Rgba<u8>, f64)],
+    brightness: impl Fn([u8; 3]) -> f64,
+    decode: impl Fn(u8) -> f64,
+    encode: impl Fn(f64) -> u8,
+) -> Rgba<u8> {
+    let (mut colour, mut share, mut alpha, mut area) = ([0.0; 3], 0.0, 0.0, 0.0);
+    for (pix, weight) in pixels {
+        let a = pix[3] as f64 * weight;
+        let s = a * brightness([pix[0], pix[1], pix[2]]);
+        for c in 0..3 {
+            colour[c] += decode(pix[c]) * s;
+        }
+        share += s;
+        alpha += a;
+        area += weight;
+    }
+    if share == 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+    let [r, g, b] = colour.map(|c| encode(c / share));
+    Rgba([r, g, b, (alpha / area).round() as u8])
+}
+
+// value of each channel with half the weight either side
+fn median(pixels: &[(Rgba<u8>, f64)]) -> Rgba<u8> {
+    let mut median = [0; 4];
+    for (c, value) in median.iter_mut().enumerate() {
+        let mut channel: Vec<(u8, f64)> = pixels.iter().map(|(pix, w)| (pix[c], *w)).collect();
+        channel.sort_unstable_by_key(|(v, _)| *v);
+        let half = channel.iter().map(|(_, w)| w).sum::<f64>() / 2.0;
+        let mut seen = 0.0;
+        for (v, w) in channel {
+            seen += w;
+            *value = v;
+            if seen >= half {
+                break;
+            }
+        }
+    }
+    Rgba(median)
+}
+
+// colour covering the most area, the first seen winning ties
+fn mode(pixels: &[(Rgba<u8>, f64)]) -> Rgba<u8> {
+    let mut areas: HashMap<[u8; 4], f64> = HashMap::new();
+    for (pix, weight) in pixels {
+        *areas.entry(pix.0).or_default() += weight;
+    }
+    let mut best = (Rgba([0, 0, 0, 0]), 0.0);
+    for (pix, _) in pixels {
+        let area = areas[&pix.0];
+        if area > best.1 {
+            best = (*pix, area);
+        }
+    }
+    best.0
+}
+
+// colour of the cell covering [x0, x1) x [y0, y1) of the image
+fn sample(
+    img: &RgbaImage,
+    (x0, x1): (f64, f64),
+    (y0, y1): (f64, f64),
+    sampling: Sampling,
+) -> Rgba<u8> {
+    let pixels = || covered(img, (x0, x1), (y0, y1));
+    let srgb = |c: u8| c as f64;
+    let to_srgb = |c: f64| c.round() as u8;
+    match sampling {
+        Sampling::Mean => mean(&pixels(), |_| 1.0, srgb, to_srgb),
+        Sampling::Median => median(&pixels()),
+        Sampling::Mode => mode(&pixels()),
+        // a zero width range covers nothing, so pick the pixel out directly
+        Sampling::Centre => {
+            let centre_x = (x0 + (x1 - x0) / 2.0).floor() as i64;
+            let centre_y = (y0 + (y1 - y0) / 2.0).floor() as i64;
+            pixel_at(img, centre_x, centre_y)
+        }
+        // a little weight for black so all-black cells still have a colour
+        Sampling::Luma => mean(&pixels(), |pix| luma(pix) + 1.0 / 255.0, srgb, to_srgb),
+        Sampling::Linear => mean(&pixels(), |_| 1.0, srgb_to_linear, linear_to_srgb),
+    }
+}
+
+pub fn pixelate(
+    img: DynamicImage,
+    pixelation: Pixelation,
+    fit: Fit,
+    sampling: Sampling,
+) -> DynamicImage {
+    eprintln!("Pixelating...");
+    let img = img.to_rgba8();
+
+    let layout = Layout::new(img.dimensions(), pixelation, fit);
+    let (output_width, output_height) = layout.cells;
+    let (cell_width, cell_height) = layout.cell_size;
+    let (origin_x, origin_y) = layout.origin;
+    eprintln!(
+        "{}x{} => {}x{} cells",
+        img.width(),
+        img.height(),
+        output_width,
+        output_height
+    );
+
+    let bar = ProgressBar::new(output_height as u64);
+    let mut output_img = RgbaImage::new(output_width, output_height);
+    for y in 0..output_height {
+        let y0 = origin_y + y as f64 * cell_height;
+        for x in 0..output_width {
+            let x0 = origin_x + x as f64 * cell_width;
+            // paint a sample of the cell's pixels into its place
+            let pix = sample(
+                &img,
+                (x0, x0 + cell_width),
+                (y0, y0 + cell_height),
+                sampling,
+            );
+            output_img.put_pixel(x, y, pix);
+        }
+        bar.inc(1);
+    }
+
+    DynamicImage::ImageRgba8(output_img)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // cells, cell size and origin of the grid laid over a 100x50 image
+    fn layout(pixelation: Pixelation, fit: Fit) -> ((u32, u32), (f64, f64), (f64, f64)) {
+        let layout = Layout::new((100, 50), pixelation, fit);
+        (layout.cells, layout.cell_size, layout.origin)
+    }
+
+    fn assert_layout(
+        pixelation: Pixelation,
+        fit: Fit,
+        (cells, cell_size, origin): ((u32, u32), (f64, f64), (f64, f64)),
+    ) {
+        let (got_cells, got_size, got_origin) = layout(pixelation, fit);
+        let close =
+            |a: (f64, f64), b: (f64, f64)| (a.0 - b.0).abs() < 1e-9 && (a.1 - b.1).abs() < 1e-9;
+        assert!(
+            got_cells == cells && close(got_size, cell_size) && close(got_origin, origin),
+            "{:?} {:?}: got {:?} {:?} {:?}",
+            pixelation,
+            fit,
+            got_cells,
+            got_size,
+            got_origin
+        );
+    }
+
+    #[test]
+    fn width_that_doesnt_divide() {
+        let third = 100.0 / 30.0;
+        assert_layout(
+            Pixelation::Width(30),
+            Fit::Resample,
+            ((30, 15), (third, third), (0.0, 0.0)),
+        );
+        // 3 pixel blocks, 10 pixels trimmed across and the height cropped to match
+        assert_layout(
+            Pixelation::Width(30),
+            Fit::Crop,
+            ((30, 15), (3.0, 3.0), (5.0, 2.0)),
+        );
+        // 4 pixel blocks, 20 pixels of padding across and the height padded to match
+        assert_layout(
+            Pixelation::Width(30),
+            Fit::Pad,
+            ((30, 15), (4.0, 4.0), (-10.0, -5.0)),
+        );
+    }
+
+    #[test]
+    fn height() {
+        assert_layout(
+            Pixelation::Height(7),
+            Fit::Resample,
+            ((14, 7), (100.0 / 14.0, 50.0 / 7.0), (0.0, 0.0)),
+        );
+        assert_layout(
+            Pixelation::Height(7),
+            Fit::Crop,
+            ((14, 7), (7.0, 7.0), (1.0, 0.0)),
+        );
+        assert_layout(
+            Pixelation::Height(7),
+            Fit::Pad,
+            ((14, 7), (8.0, 8.0), (-6.0, -3.0)),
+        );
+    }
+
+    #[test]
+    fn cell_size() {
+        assert_layout(
+            Pixelation::CellSize(8),
+            Fit::Resample,
+            ((13, 6), (100.0 / 13.0, 50.0 / 6.0), (0.0, 0.0)),
+        );
+        assert_layout(
+            Pixelation::CellSize(8),
+            Fit::Crop,
+            ((12, 6), (8.0, 8.0), (2.0, 1.0)),
+        );
+        assert_layout(
+            Pixelation::CellSize(8),
+            Fit::Pad,
+            ((13, 7), (8.0, 8.0), (-2.0, -3.0)),
+        );
+    }
+
+    #[test]
+    fn larger_than_the_image_stops_at_a_pixel_a_cell() {
+        for fit in [Fit::Resample, Fit::Crop, Fit::Pad] {
+            for pixelation in [
+                Pixelation::Width(2000),
+                Pixelation::Height(2000),
+                Pixelation::CellSize(0),
+            ] {
+                assert_layout(pixelation, fit, ((100, 50), (1.0, 1.0), (0.0, 0.0)));
+            }
+        }
+    }
+}
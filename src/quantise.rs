@@ -0,0 +1,352 @@
+use std::{collections::HashMap, str::FromStr};
+
+use color_quant::NeuQuant;
+use image::{RgbImage, Rgba, RgbaImage};
+
+// k-means only needs a rough picture of the colours, so it looks at a sample
+const KMEANS_SAMPLE: usize = 1 << 16;
+const KMEANS_ROUNDS: usize = 8;
+
+// how a palette is chosen for an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantiser {
+    // neural net quantiser from the gif crate, speed 1 (best) to 30 (fastest)
+    NeuQuant(i32),
+    // repeatedly split the box of colours with the widest spread at its median
+    MedianCut,
+    // median cut refined by a few rounds of k-means clustering
+    KMeans,
+}
+
+impl Default for Quantiser {
+    fn default() -> Self {
+        Quantiser::NeuQuant(1)
+    }
+}
+
+impl FromStr for Quantiser {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_whitespace().collect::<String>().to_lowercase();
+        let (name, speed) = match s.split_once(':') {
+            Some((name, speed)) => (name, Some(speed)),
+            None => (s.as_str(), None),
+        };
+        match (name, speed) {
+            ("neuquant" | "nq", None) => Ok(Quantiser::NeuQuant(1)),
+            ("neuquant" | "nq", Some(speed)) => match speed.parse::<i32>() {
+                Ok(speed) if (1..=30).contains(&speed) => Ok(Quantiser::NeuQuant(speed)),
+                _ => Err(format!("neuquant speed '{}' should be 1-30", speed)),
+            },
+            ("mediancut" | "median-cut" | "median", None) => Ok(Quantiser::MedianCut),
+            ("kmeans" | "k-means", None) => Ok(Quantiser::KMeans),
+            _ => Err(format!(
+                "quantiser '{}' should be \"neuquant[:speed]\", \"median-cut\" or \"k-means\"",
+                s
+            )),
+        }
+    }
+}
+
+// how colours that fall between palette entries are spread out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dither {
+    // nearest palette colour only
+    #[default]
+    None,
+    // error diffusion onto the next four pixels
+    FloydSteinberg,
+    // error diffusion losing a quarter of the error, keeps more contrast
+    Atkinson,
+    // 8x8 ordered threshold pattern, stable between frames
+    Bayer,
+}
+
+impl FromStr for Dither {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_whitespace().collect::<String>().to_lowercase();
+        match s.as_str() {
+            "none" | "off" => Ok(Dither::None),
+            "floyd-steinberg" | "floydsteinberg" | "fs" => Ok(Dither::FloydSteinberg),
+            "atkinson" => Ok(Dither::Atkinson),
+            "bayer" | "ordered" => Ok(Dither::Bayer),
+            _ => Err(format!(
+                "dither '{}' should be \"none\", \"floyd-steinberg\", \"atkinson\" or \"bayer\"",
+                s
+            )),
+        }
+    }
+}
+
+// 8x8 bayer threshold matrix, values 0-63
+const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+// a set of colours, and the nearest one to any colour asked for
+#[derive(Debug, Clone)]
+pub struct Palette {
+    colours: Vec<[u8; 3]>,
+    // lookups repeat a lot between slides, so remember the answers
+    cache: HashMap<[u8; 3], u8>,
+}
+
+impl Palette {
+    // up to 256 colours
+    pub fn new(colours: Vec<[u8; 3]>) -> Palette {
+        assert!(
+            !colours.is_empty() && colours.len() <= 256,
+            "a palette needs 1-256 colours"
+        );
+        Palette {
+            colours,
+            cache: HashMap::new(),
+        }
+    }
+
+    // a palette of at most size colours picked from pixels
+    pub fn build(quantiser: &Quantiser, pixels: &[[u8; 3]], size: usize) -> Palette {
+        let size = size.clamp(1, 256);
+        if pixels.is_empty() {
+            return Palette::new(vec![[0, 0, 0]]);
+        }
+        let colours = match quantiser {
+            Quantiser::NeuQuant(speed) => {
+                let rgba: Vec<u8> = pixels
+                    .iter()
+                    .flat_map(|[r, g, b]| [*r, *g, *b, 255])
+                    .collect();
+                NeuQuant::new(*speed, size, &rgba)
+                    .color_map_rgb()
+                    .chunks_exact(3)
+                    .map(|c| [c[0], c[1], c[2]])
+                    .collect()
+            }
+            Quantiser::MedianCut => median_cut(pixels.to_vec(), size),
+            Quantiser::KMeans => {
+                let step = pixels.len().div_ceil(KMEANS_SAMPLE).max(1);
+                let sample: Vec<[u8; 3]> = pixels.iter().step_by(step).copied().collect();
+                k_means(&sample, median_cut(sample.clone(), size))
+            }
+        };
+        Palette::new(colours)
+    }
+
+    pub fn colours(&self) -> &[[u8; 3]] {
+        &self.colours
+    }
+
+    pub fn len(&self) -> usize {
+        self.colours.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colours.is_empty()
+    }
+
+    // flat r, g, b bytes as gif wants them
+    pub fn rgb(&self) -> Vec<u8> {
+        self.colours.iter().flatten().copied().collect()
+    }
+
+    // closest colour in the palette, without touching the cache so it can be shared between threads
+    pub fn nearest(&self, colour: [u8; 3]) -> [u8; 3] {
+        self.colours[nearest(&self.colours, colour)]
+    }
+
+    // moves every pixel onto the palette, keeping its alpha
+    pub fn snap(&mut self, img: &mut RgbaImage) {
+        for pix in img.pixels_mut() {
+            let index = self.index_of([pix[0], pix[1], pix[2]]);
+            let [r, g, b] = self.colours[index as usize];
+            *pix = Rgba([r, g, b, pix[3]]);
+        }
+    }
+
+    pub fn index_of(&mut self, colour: [u8; 3]) -> u8 {
+        if let Some(&index) = self.cache.get(&colour) {
+            return index;
+        }
+        let index = nearest(&self.colours, colour) as u8;
+        self.cache.insert(colour, index);
+        index
+    }
+
+    // average distance from each colour to its closest neighbour, in the widest channel
+    fn gap(&self) -> f32 {
+        let gaps = self.colours.iter().enumerate().filter_map(|(i, a)| {
+            self.colours
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, b)| (0..3).map(|c| a[c].abs_diff(b[c])).max().unwrap_or(0))
+                .min()
+        });
+        let (sum, count) = gaps.fold((0.0, 0), |(sum, count), gap| (sum + gap as f32, count + 1));
+        if count == 0 {
+            0.0
+        } else {
+            sum / count as f32
+        }
+    }
+
+    // palette index of every pixel, row by row
+    pub fn map(&mut self, img: &RgbImage, dither: &Dither) -> Vec<u8> {
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        match dither {
+            Dither::None => img.pixels().map(|pix| self.index_of(pix.0)).collect(),
+            Dither::Bayer => {
+                // spread thresholds over the typical gap between neighbouring palette colours
+                let spread = self.gap();
+                img.enumerate_pixels()
+                    .map(|(x, y, pix)| {
+                        let threshold = BAYER[y as usize % 8][x as usize % 8] as f32;
+                        let offset = ((threshold + 0.5) / 64.0 - 0.5) * spread;
+                        self.index_of(
+                            pix.0
+                                .map(|c| (c as f32 + offset).round().clamp(0.0, 255.0) as u8),
+                        )
+                    })
+                    .collect()
+            }
+            Dither::FloydSteinberg | Dither::Atkinson => {
+                // (dx, dy, share) of the error handed on to each neighbour
+                let spread: &[(isize, usize, f32)] = if *dither == Dither::FloydSteinberg {
+                    &[
+                        (1, 0, 7.0 / 16.0),
+                        (-1, 1, 3.0 / 16.0),
+                        (0, 1, 5.0 / 16.0),
+                        (1, 1, 1.0 / 16.0),
+                    ]
+                } else {
+                    &[
+                        (1, 0, 1.0 / 8.0),
+                        (2, 0, 1.0 / 8.0),
+                        (-1, 1, 1.0 / 8.0),
+                        (0, 1, 1.0 / 8.0),
+                        (1, 1, 1.0 / 8.0),
+                        (0, 2, 1.0 / 8.0),
+                    ]
+                };
+                let mut colours: Vec<[f32; 3]> =
+                    img.pixels().map(|pix| pix.0.map(|c| c as f32)).collect();
+                let mut indices = Vec::with_capacity(width * height);
+                for y in 0..height {
+                    for x in 0..width {
+                        let wanted = colours[y * width + x].map(|c| c.round().clamp(0.0, 255.0));
+                        let index = self.index_of(wanted.map(|c| c as u8));
+                        indices.push(index);
+
+                        let got = self.colours[index as usize];
+                        let error = [0, 1, 2].map(|c| wanted[c] - got[c] as f32);
+                        for (dx, dy, share) in spread {
+                            let (nx, ny) = (x as isize + dx, y + dy);
+                            if nx < 0 || nx as usize >= width || ny >= height {
+                                continue;
+                            }
+                            let neighbour = &mut colours[ny * width + nx as usize];
+                            for c in 0..3 {
+                                neighbour[c] += error[c] * share;
+                            }
+                        }
+                    }
+                }
+                indices
+            }
+        }
+    }
+}
+
+fn distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+    (0..3)
+        .map(|c| (a[c] as i32 - b[c] as i32).pow(2) as u32)
+        .sum()
+}
+
+fn nearest(colours: &[[u8; 3]], colour: [u8; 3]) -> usize {
+    colours
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, candidate)| distance(**candidate, colour))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+fn mean(pixels: &[[u8; 3]]) -> [u8; 3] {
+    let mut sum = [0u64; 3];
+    for pix in pixels {
+        for c in 0..3 {
+            sum[c] += pix[c] as u64;
+        }
+    }
+    let n = pixels.len().max(1) as u64;
+    sum.map(|c| ((c + n / 2) / n) as u8)
+}
+
+// (channel, spread) of the channel with the widest range of values
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    (0..3)
+        .map(|c| {
+            let min = pixels.iter().map(|pix| pix[c]).min().unwrap_or(0);
+            let max = pixels.iter().map(|pix| pix[c]).max().unwrap_or(0);
+            (c, max - min)
+        })
+        .max_by_key(|(_, spread)| *spread)
+        .unwrap()
+}
+
+fn median_cut(pixels: Vec<[u8; 3]>, size: usize) -> Vec<[u8; 3]> {
+    let mut boxes = vec![pixels];
+    while boxes.len() < size {
+        // split whichever box covers the widest range of colour
+        let (i, (channel, spread)) = boxes
+            .iter()
+            .enumerate()
+            .map(|(i, pixels)| (i, widest_channel(pixels)))
+            .max_by_key(|(_, (_, spread))| *spread)
+            .unwrap();
+        if spread == 0 {
+            break;
+        }
+        let mut pixels = boxes.swap_remove(i);
+        pixels.sort_unstable_by_key(|pix| pix[channel]);
+        let upper = pixels.split_off(pixels.len() / 2);
+        boxes.push(pixels);
+        boxes.push(upper);
+    }
+    boxes.iter().map(|pixels| mean(pixels)).collect()
+}
+
+fn k_means(pixels: &[[u8; 3]], mut centres: Vec<[u8; 3]>) -> Vec<[u8; 3]> {
+    for _ in 0..KMEANS_ROUNDS {
+        let mut clusters = vec![Vec::new(); centres.len()];
+        for pix in pixels {
+            clusters[nearest(&centres, *pix)].push(*pix);
+        }
+        let moved: Vec<[u8; 3]> = clusters
+            .iter()
+            .zip(&centres)
+            .map(|(cluster, centre)| {
+                // an empty cluster keeps its old centre
+                if cluster.is_empty() {
+                    *centre
+                } else {
+                    mean(cluster)
+                }
+            })
+            .collect();
+        if moved == centres {
+            break;
+        }
+        centres = moved;
+    }
+    centres
+}
@@ -0,0 +1,149 @@
+use std::{fs, path::Path, str::FromStr};
+
+use image::RgbaImage;
+
+use crate::{Palette, Quantiser};
+
+const PICO8: [u32; 16] = [
+    0x000000, 0x1D2B53, 0x7E2553, 0x008751, 0xAB5236, 0x5F574F, 0xC2C3C7, 0xFFF1E8, 0xFF004D,
+    0xFFA300, 0xFFEC27, 0x00E436, 0x29ADFF, 0x83769C, 0xFF77A8, 0xFFCCAA,
+];
+
+// original dmg greens, darkest first
+const GAME_BOY: [u32; 4] = [0x0F380F, 0x306230, 0x8BAC0F, 0x9BBC0F];
+
+// the 2C02's 64 entries, blacks repeated as on the hardware
+const NES: [u32; 64] = [
+    0x7C7C7C, 0x0000FC, 0x0000BC, 0x4428BC, 0x940084, 0xA80020, 0xA81000, 0x881400, 0x503000,
+    0x007800, 0x006800, 0x005800, 0x004058, 0x000000, 0x000000, 0x000000, 0xBCBCBC, 0x0078F8,
+    0x0058F8, 0x6844FC, 0xD800CC, 0xE40058, 0xF83800, 0xE45C10, 0xAC7C00, 0x00B800, 0x00A800,
+    0x00A844, 0x008888, 0x000000, 0x000000, 0x000000, 0xF8F8F8, 0x3CBCFC, 0x6888FC, 0x9878F8,
+    0xF878F8, 0xF85898, 0xF87858, 0xFCA044, 0xF8B800, 0xB8F818, 0x58D854, 0x58F898, 0x00E8D8,
+    0x787878, 0x000000, 0x000000, 0xFCFCFC, 0xA4E4FC, 0xB8B8F8, 0xD8B8F8, 0xF8B8F8, 0xF8A4C0,
+    0xF0D0B0, 0xFCE0A8, 0xF8D878, 0xD8F878, 0xB8F8B8, 0xB8F8D8, 0x00FCFC, 0xF8D8F8, 0x000000,
+    0x000000,
+];
+
+// full 16 colour rgbi palette
+const CGA: [u32; 16] = [
+    0x000000, 0x0000AA, 0x00AA00, 0x00AAAA, 0xAA0000, 0xAA00AA, 0xAA5500, 0xAAAAAA, 0x555555,
+    0x5555FF, 0x55FF55, 0x55FFFF, 0xFF5555, 0xFF55FF, 0xFFFF55, 0xFFFFFF,
+];
+
+// where the colours of a limited palette come from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaletteChoice {
+    Pico8,
+    GameBoy,
+    Nes,
+    Cga,
+    // a .hex (one RRGGBB per line) or GIMP .gpl file
+    File(String),
+    // this many colours picked from the image itself
+    KMeans(usize),
+}
+
+impl FromStr for PaletteChoice {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let extension = Path::new(s)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        if let Some("hex" | "gpl") = extension.as_deref() {
+            return Ok(PaletteChoice::File(s.to_owned()));
+        }
+
+        let s = s.split_whitespace().collect::<String>().to_lowercase();
+        match s.split_once(':') {
+            Some(("kmeans" | "k-means", colours)) => match colours.parse::<usize>() {
+                Ok(colours) if (1..=256).contains(&colours) => Ok(PaletteChoice::KMeans(colours)),
+                _ => Err(format!("k-means palette size '{}' should be 1-256", colours)),
+            },
+            _ => match s.as_str() {
+                "pico8" | "pico-8" => Ok(PaletteChoice::Pico8),
+                "gameboy" | "game-boy" | "gb" => Ok(PaletteChoice::GameBoy),
+                "nes" => Ok(PaletteChoice::Nes),
+                "cga" => Ok(PaletteChoice::Cga),
+                _ => Err(format!(
+                    "palette '{}' should be \"pico8\", \"gameboy\", \"nes\", \"cga\", \"k-means:N\" or a .hex / .gpl file",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl PaletteChoice {
+    // k-means palettes are learnt from the visible pixels of img
+    pub fn palette(&self, img: &RgbaImage) -> Result<Palette, String> {
+        let colours = match self {
+            PaletteChoice::Pico8 => from_hex_codes(&PICO8),
+            PaletteChoice::GameBoy => from_hex_codes(&GAME_BOY),
+            PaletteChoice::Nes => from_hex_codes(&NES),
+            PaletteChoice::Cga => from_hex_codes(&CGA),
+            PaletteChoice::File(path) => load(path)?,
+            PaletteChoice::KMeans(colours) => {
+                let pixels: Vec<[u8; 3]> = img
+                    .pixels()
+                    .filter(|pix| pix[3] > 0)
+                    .map(|pix| [pix[0], pix[1], pix[2]])
+                    .collect();
+                return Ok(Palette::build(&Quantiser::KMeans, &pixels, *colours));
+            }
+        };
+        Ok(Palette::new(colours))
+    }
+}
+
+fn from_hex_codes(codes: &[u32]) -> Vec<[u8; 3]> {
+    let mut colours: Vec<[u8; 3]> = Vec::new();
+    for code in codes {
+        let colour = [(code >> 16) as u8, (code >> 8) as u8, *code as u8];
+        if !colours.contains(&colour) {
+            colours.push(colour);
+        }
+    }
+    colours
+}
+
+fn load(path: &str) -> Result<Vec<[u8; 3]>, String> {
+    let text =
+        fs::read_to_string(path).map_err(|e| format!("couldn't read palette {}: {}", path, e))?;
+    let gpl = path.to_lowercase().ends_with(".gpl");
+
+    let mut codes = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let code = if gpl {
+            // header lines, then "R G B name"
+            if line.starts_with('#') || line.starts_with("GIMP") || line.contains(':') {
+                continue;
+            }
+            let channels: Vec<u32> = line
+                .split_whitespace()
+                .take(3)
+                .map(|c| c.parse::<u8>().map(u32::from))
+                .collect::<Result<_, _>>()
+                .map_err(|_| format!("bad palette line '{}' in {}", line, path))?;
+            match channels[..] {
+                [r, g, b] => (r << 16) | (g << 8) | b,
+                _ => return Err(format!("bad palette line '{}' in {}", line, path)),
+            }
+        } else {
+            let hex = line.trim_start_matches('#');
+            match u32::from_str_radix(hex, 16) {
+                Ok(code) if hex.len() == 6 => code,
+                _ => return Err(format!("bad palette line '{}' in {}", line, path)),
+            }
+        };
+        codes.push(code);
+    }
+
+    let colours = from_hex_codes(&codes);
+    if colours.is_empty() || colours.len() > 256 {
+        return Err(format!("palette {} should have 1-256 colours", path));
+    }
+    Ok(colours)
+}
@@ -0,0 +1,110 @@
+use std::str::FromStr;
+
+use image::{GrayImage, Luma};
+
+// where light and dark are split when seeding life from an image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Threshold {
+    // pixels brighter than this luma 0-255 are light
+    Value(u8),
+    // the split that best separates the image into two groups of brightness
+    Otsu,
+    // the image's average brightness
+    Mean,
+    // the brightness this percentage of pixels are at or below
+    Percentile(f32),
+}
+
+impl Default for Threshold {
+    // halfway, as image's BiLevel map
+    fn default() -> Self {
+        Threshold::Value(127)
+    }
+}
+
+impl FromStr for Threshold {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_whitespace().collect::<String>().to_lowercase();
+        let percentile = s
+            .strip_prefix("percentile:")
+            .or(s.strip_prefix('p'))
+            .or(s.strip_suffix('%'));
+        match (s.as_str(), percentile) {
+            ("otsu" | "auto", _) => Ok(Threshold::Otsu),
+            ("mean" | "average", _) => Ok(Threshold::Mean),
+            (_, Some(percentile)) => match percentile.parse::<f32>() {
+                Ok(percentile) if (0.0..=100.0).contains(&percentile) => {
+                    Ok(Threshold::Percentile(percentile))
+                }
+                _ => Err(format!("percentile '{}' should be 0-100", percentile)),
+            },
+            _ => s.parse::<u8>().map(Threshold::Value).map_err(|_| {
+                format!(
+                    "threshold '{}' should be 0-255, \"otsu\", \"mean\" or a percentile like \"p30\"",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+impl Threshold {
+    // luma level at and below which pixels count as dark
+    pub fn level(&self, luma: &GrayImage) -> u8 {
+        let mut histogram = [0u64; 256];
+        for pix in luma.pixels() {
+            histogram[pix[0] as usize] += 1;
+        }
+        let total: u64 = histogram.iter().sum::<u64>().max(1);
+
+        match *self {
+            Threshold::Value(level) => level,
+            Threshold::Mean => {
+                let sum: u64 = (0..256).map(|v| v as u64 * histogram[v]).sum();
+                (sum / total) as u8
+            }
+            Threshold::Percentile(percentile) => {
+                let wanted = (percentile as f64 / 100.0 * total as f64).ceil() as u64;
+                let mut seen = 0;
+                for (v, count) in histogram.iter().enumerate() {
+                    seen += count;
+                    if seen >= wanted.max(1) {
+                        return v as u8;
+                    }
+                }
+                255
+            }
+            Threshold::Otsu => {
+                // pick the split with the greatest variance between the dark and light groups
+                let sum: f64 = (0..256).map(|v| v as f64 * histogram[v] as f64).sum();
+                let (mut dark_count, mut dark_sum) = (0.0, 0.0);
+                let (mut best, mut best_variance) = (0, -1.0);
+                for (v, count) in histogram.iter().enumerate() {
+                    dark_count += *count as f64;
+                    dark_sum += v as f64 * *count as f64;
+                    let light_count = total as f64 - dark_count;
+                    if dark_count == 0.0 || light_count == 0.0 {
+                        continue;
+                    }
+                    let difference = dark_sum / dark_count - (sum - dark_sum) / light_count;
+                    let variance = dark_count * light_count * difference * difference;
+                    if variance > best_variance {
+                        best = v;
+                        best_variance = variance;
+                    }
+                }
+                best as u8
+            }
+        }
+    }
+
+    // 1 where the image is light, 0 where dark
+    pub fn split(&self, luma: &GrayImage) -> GrayImage {
+        let level = self.level(luma);
+        eprintln!("threshold = {}", level);
+        GrayImage::from_fn(luma.width(), luma.height(), |x, y| {
+            Luma([(luma.get_pixel(x, y)[0] > level) as u8])
+        })
+    }
+}
@@ -0,0 +1,115 @@
+use std::str::FromStr;
+
+use image::{Rgba, RgbaImage};
+
+use crate::{BackgroundColour, FrameSink};
+
+// a target size given as "WxH", or just "W" / "xH" to keep the aspect ratio
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl Size {
+    // the full size for an image of these dimensions
+    pub fn resolve(&self, (width, height): (u32, u32)) -> (u32, u32) {
+        let scaled = |length: u32, from: u32, to: u32| {
+            ((length as u64 * to as u64 + from as u64 / 2) / from.max(1) as u64).max(1) as u32
+        };
+        match (self.width, self.height) {
+            (Some(w), Some(h)) => (w, h),
+            (Some(w), None) => (w, scaled(height, width, w)),
+            (None, Some(h)) => (scaled(width, height, h), h),
+            (None, None) => (width, height),
+        }
+    }
+}
+
+impl FromStr for Size {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_whitespace().collect::<String>().to_lowercase();
+        let (width, height) = s.split_once('x').unwrap_or((&s, ""));
+        let parse = |length: &str| match length {
+            "" => Ok(None),
+            _ => match length.parse::<u32>() {
+                Ok(length) if length > 0 => Ok(Some(length)),
+                _ => Err(format!("size '{}' should be \"WxH\", \"W\" or \"xH\"", s)),
+            },
+        };
+        let size = Size {
+            width: parse(width)?,
+            height: parse(height)?,
+        };
+        if size.width.is_none() && size.height.is_none() {
+            return Err(format!("size '{}' should be \"WxH\", \"W\" or \"xH\"", s));
+        }
+        Ok(size)
+    }
+}
+
+// nearest-neighbour blow up of a slide to width x height, each cell becoming a solid block.
+// grid lines take up the first row and column of every block after the first, along either axis
+// only if blocks are at least 2 pixels across, otherwise the lines would cover every cell
+pub fn upscale(
+    slide: &RgbaImage,
+    (width, height): (u32, u32),
+    grid: Option<Rgba<u8>>,
+) -> RgbaImage {
+    let (cells_wide, cells_high) = slide.dimensions();
+    // cell each output column / row falls in
+    let column = |x: u32| (x as u64 * cells_wide as u64 / width as u64) as u32;
+    let row = |y: u32| (y as u64 * cells_high as u64 / height as u64) as u32;
+
+    let lines_x = width >= 2 * cells_wide;
+    let lines_y = height >= 2 * cells_high;
+
+    RgbaImage::from_fn(width, height, |x, y| {
+        let (cx, cy) = (column(x), row(y));
+        if let Some(colour) = grid {
+            let edge_x = lines_x && x > 0 && column(x - 1) != cx;
+            let edge_y = lines_y && y > 0 && row(y - 1) != cy;
+            if edge_x || edge_y {
+                return colour;
+            }
+        }
+        *slide.get_pixel(cx, cy)
+    })
+}
+
+// upscales every slide before handing it on to another sink
+pub struct UpscaleSink {
+    sink: Box<dyn FrameSink>,
+    dimensions: (u32, u32),
+    grid: Option<Rgba<u8>>,
+}
+
+impl UpscaleSink {
+    // dimensions are the upscaled size, the one the inner sink was made for
+    pub fn new(
+        sink: Box<dyn FrameSink>,
+        dimensions: (u32, u32),
+        grid: Option<BackgroundColour>,
+    ) -> UpscaleSink {
+        UpscaleSink {
+            sink,
+            dimensions,
+            grid: grid.map(|colour| {
+                let [r, g, b] = colour.rgb();
+                Rgba([r, g, b, 255])
+            }),
+        }
+    }
+}
+
+impl FrameSink for UpscaleSink {
+    fn write_frame(&mut self, slide: &RgbaImage, delay_ms: u16) {
+        let img = upscale(slide, self.dimensions, self.grid);
+        self.sink.write_frame(&img, delay_ms);
+    }
+
+    fn finish(&mut self) {
+        self.sink.finish();
+    }
+}
@@ -0,0 +1,139 @@
+use std::{ffi::CStr, fs, ptr};
+
+use image::RgbaImage;
+use libwebp_sys::*;
+
+use crate::{FrameSink, Loop};
+
+// how hard libwebp works on each frame
+#[derive(Debug, Clone, Copy)]
+pub struct WebpSettings {
+    pub lossless: bool,
+    // 0-100. image quality when lossy, compression effort when lossless
+    pub quality: f32,
+    // 0-6. slower methods give smaller files
+    pub method: i32,
+}
+
+impl Default for WebpSettings {
+    fn default() -> Self {
+        WebpSettings {
+            lossless: false,
+            quality: 75.0,
+            method: 4,
+        }
+    }
+}
+
+// animated webp keeping the slides' own alpha, no background is applied.
+// frames are compressed as they arrive and the file is written by finish()
+pub struct WebpSink {
+    encoder: *mut WebPAnimEncoder,
+    config: WebPConfig,
+    file_path: String,
+    timestamp_ms: i32,
+}
+
+impl WebpSink {
+    pub fn new(
+        file_path: &str,
+        (width, height): (u32, u32),
+        settings: WebpSettings,
+        loops: Loop,
+    ) -> WebpSink {
+        eprintln!("creating webp at {}", file_path);
+
+        let mut config = unsafe {
+            let mut config = std::mem::MaybeUninit::uninit();
+            let ok = WebPConfigInitInternal(
+                config.as_mut_ptr(),
+                WebPPreset::WEBP_PRESET_DEFAULT,
+                settings.quality,
+                WEBP_ENCODER_ABI_VERSION as _,
+            );
+            assert!(ok != 0, "couldn't set up webp encoder config");
+            config.assume_init()
+        };
+        config.lossless = settings.lossless as _;
+        config.quality = settings.quality;
+        config.method = settings.method;
+        assert!(
+            unsafe { WebPValidateConfig(&config) } != 0,
+            "webp quality should be 0-100 and method 0-6"
+        );
+
+        let encoder = unsafe {
+            let mut options = std::mem::MaybeUninit::uninit();
+            let ok =
+                WebPAnimEncoderOptionsInitInternal(options.as_mut_ptr(), WEBP_MUX_ABI_VERSION as _);
+            assert!(ok != 0, "couldn't set up webp animation options");
+            let mut options = options.assume_init();
+            options.anim_params.loop_count = match loops {
+                Loop::Infinite => 0,
+                Loop::Times(times) => times as _,
+            };
+            WebPAnimEncoderNewInternal(width as _, height as _, &options, WEBP_MUX_ABI_VERSION as _)
+        };
+        assert!(!encoder.is_null(), "couldn't create webp encoder");
+
+        WebpSink {
+            encoder,
+            config,
+            file_path: file_path.to_owned(),
+            timestamp_ms: 0,
+        }
+    }
+
+    fn error(&self) -> String {
+        unsafe { CStr::from_ptr(WebPAnimEncoderGetError(self.encoder)) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+impl FrameSink for WebpSink {
+    fn write_frame(&mut self, slide: &RgbaImage, delay_ms: u16) {
+        let (width, height) = slide.dimensions();
+        unsafe {
+            let mut picture = std::mem::MaybeUninit::uninit();
+            let ok = WebPPictureInitInternal(picture.as_mut_ptr(), WEBP_ENCODER_ABI_VERSION as _);
+            assert!(ok != 0, "couldn't set up webp frame");
+            let mut picture = picture.assume_init();
+            picture.use_argb = 1;
+            picture.width = width as _;
+            picture.height = height as _;
+
+            let ok = WebPPictureImportRGBA(&mut picture, slide.as_ptr(), (width * 4) as _) != 0
+                && WebPAnimEncoderAdd(self.encoder, &mut picture, self.timestamp_ms, &self.config)
+                    != 0;
+            WebPPictureFree(&mut picture);
+            assert!(ok, "couldn't encode webp frame: {}", self.error());
+        }
+        self.timestamp_ms += delay_ms as i32;
+    }
+
+    fn finish(&mut self) {
+        let mut data = WebPData::default();
+        unsafe {
+            // a last empty frame tells the encoder how long the final slide stays up
+            let ok = WebPAnimEncoderAdd(
+                self.encoder,
+                ptr::null_mut(),
+                self.timestamp_ms,
+                ptr::null(),
+            ) != 0
+                && WebPAnimEncoderAssemble(self.encoder, &mut data) != 0;
+            assert!(ok, "couldn't assemble webp: {}", self.error());
+
+            let bytes = std::slice::from_raw_parts(data.bytes, data.size);
+            fs::write(&self.file_path, bytes).expect("couldn't save webp to path:");
+            WebPDataClear(&mut data);
+        }
+    }
+}
+
+impl Drop for WebpSink {
+    fn drop(&mut self) {
+        unsafe { WebPAnimEncoderDelete(self.encoder) }
+    }
+}
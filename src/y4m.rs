@@ -0,0 +1,108 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+use image::{RgbImage, RgbaImage};
+
+use crate::{create_background, BackgroundColour, ColourSpace, FrameSink};
+
+// raw YUV4MPEG2 video, flattened onto the background colour. 4:2:0 full range (JFIF) chroma,
+// so it can be piped straight into ffmpeg and friends. a file path of "-" writes to stdout
+pub struct Y4mSink {
+    writer: Box<dyn Write>,
+    background: BackgroundColour,
+    space: ColourSpace,
+    fps: u32,
+    // frame buffer reused between slides: Y plane, then U, then V
+    planes: Vec<u8>,
+}
+
+impl Y4mSink {
+    pub fn new(
+        file_path: &str,
+        (width, height): (u32, u32),
+        background: BackgroundColour,
+        space: ColourSpace,
+        fps: u32,
+    ) -> Y4mSink {
+        let mut writer: Box<dyn Write> = if file_path == "-" {
+            eprintln!("streaming y4m to stdout");
+            Box::new(BufWriter::new(io::stdout()))
+        } else {
+            eprintln!("creating y4m at {}", file_path);
+            let file = File::create(file_path).expect("couldn't save y4m to path:");
+            Box::new(BufWriter::new(file))
+        };
+
+        writeln!(
+            writer,
+            "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C420jpeg XCOLORRANGE=FULL",
+            width, height, fps
+        )
+        .expect("couldn't write y4m header");
+
+        Y4mSink {
+            writer,
+            background,
+            space,
+            fps,
+            planes: Vec::new(),
+        }
+    }
+
+    fn fill_planes(&mut self, img: &RgbImage) {
+        let (width, height) = img.dimensions();
+        // odd dimensions round up, the last chroma sample covering a half block
+        let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+        let luma_len = (width * height) as usize;
+        let chroma_len = (chroma_width * chroma_height) as usize;
+
+        self.planes.clear();
+        self.planes.resize(luma_len + 2 * chroma_len, 0);
+        let (luma, chroma) = self.planes.split_at_mut(luma_len);
+        let (u, v) = chroma.split_at_mut(chroma_len);
+
+        for (x, y, pix) in img.enumerate_pixels() {
+            let [r, g, b] = pix.0.map(|c| c as f32);
+            luma[(y * width + x) as usize] = (0.299 * r + 0.587 * g + 0.114 * b).round() as u8;
+        }
+
+        for cy in 0..chroma_height {
+            for cx in 0..chroma_width {
+                // average whichever pixels of the 2x2 block lie inside the image
+                let (mut cb, mut cr, mut n) = (0.0, 0.0, 0.0);
+                for y in (cy * 2)..(cy * 2 + 2).min(height) {
+                    for x in (cx * 2)..(cx * 2 + 2).min(width) {
+                        let [r, g, b] = img.get_pixel(x, y).0.map(|c| c as f32);
+                        cb += 128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b;
+                        cr += 128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b;
+                        n += 1.0;
+                    }
+                }
+                let i = (cy * chroma_width + cx) as usize;
+                u[i] = (cb / n).round().clamp(0.0, 255.0) as u8;
+                v[i] = (cr / n).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+}
+
+impl FrameSink for Y4mSink {
+    fn write_frame(&mut self, slide: &RgbaImage, delay_ms: u16) {
+        let img = create_background(slide.clone(), &self.background, self.space).to_rgb8();
+        self.fill_planes(&img);
+        // fixed frame rate, so long delays repeat the frame. wide enough for any delay and rate
+        let repeats = ((delay_ms as u64 * self.fps as u64 + 500) / 1000).max(1);
+        for _ in 0..repeats {
+            self.writer
+                .write_all(b"FRAME\n")
+                .and_then(|_| self.writer.write_all(&self.planes))
+                .expect("couldn't write y4m frame");
+        }
+    }
+
+    fn finish(&mut self) {
+        self.writer.flush().expect("couldn't write y4m");
+    }
+}